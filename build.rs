@@ -1,15 +1,160 @@
 extern crate gl_generator;
 
-use gl_generator::{Registry, Api, Profile, Fallbacks, GlobalGenerator};
+use gl_generator::{Registry, Api, Profile, Fallbacks, GlobalGenerator, Generator};
+use gl_generator::generators::gen_parameters;
 use std::env;
 use std::fs::File;
+use std::io;
 use std::path::Path;
 
+/// Generates wrappers around the functions of `gl.rs` that check for errors after each call,
+/// included by `src/checked_gl.rs`.
+struct CheckedGenerator;
+
+impl Generator for CheckedGenerator {
+    fn write<W>(&self, registry: &Registry, dest: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        for cmd in registry.cmds.iter() {
+            let name = &*cmd.proto.ident;
+            // Checking for errors consumes them, so `GetError` itself is passed through.
+            if name == "GetError" {
+                continue;
+            }
+            let params = gen_parameters(cmd, true, true).join(", ");
+            let idents = gen_parameters(cmd, true, false).join(", ");
+            let ty = &*cmd.proto.ty;
+            writeln!(dest, "#[track_caller]")?;
+            writeln!(dest, "#[inline]")?;
+            if ty == "()" {
+                writeln!(dest, "pub unsafe fn {}({}) {{", name, params)?;
+                writeln!(dest, "    crate::gl::{}({});", name, idents)?;
+                writeln!(dest, "    check_errors(\"gl{}\", Location::caller());", name)?;
+            } else {
+                writeln!(dest, "pub unsafe fn {}({}) -> {} {{", name, params, ty)?;
+                writeln!(dest, "    let result = crate::gl::{}({});", name, idents)?;
+                writeln!(dest, "    check_errors(\"gl{}\", Location::caller());", name)?;
+                writeln!(dest, "    result")?;
+            }
+            writeln!(dest, "}}\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Functions of the fake OpenGL with behavior beyond recording, defined in `src/testing`.
+const MOCKED: &[&str] = &["BindFramebuffer",
+                          "BufferData",
+                          "BufferSubData",
+                          "CheckFramebufferStatus",
+                          "CompressedTexImage2D",
+                          "CompressedTexSubImage2D",
+                          "CreateProgram",
+                          "CreateShader",
+                          "GetAttribLocation",
+                          "GetError",
+                          "GetIntegerv",
+                          "GetProgramiv",
+                          "GetQueryObjectuiv",
+                          "GetShaderiv",
+                          "GetString",
+                          "GetUniformLocation",
+                          "PixelStorei",
+                          "ReadPixels",
+                          "ShaderSource",
+                          "TexImage2D",
+                          "TexSubImage2D",
+                          "Viewport"];
+
+/// Generates the recording stubs of the fake OpenGL, included by `src/testing/stubs.rs`.
+struct StubGenerator;
+
+impl Generator for StubGenerator {
+    fn write<W>(&self, registry: &Registry, dest: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        // The stubs module imports `raw` itself.
+        let rust_ty = |ty: &str| ty.replace("__gl_imports::", "");
+        for cmd in registry.cmds.iter() {
+            let name = &*cmd.proto.ident;
+            if MOCKED.contains(&name) {
+                continue;
+            }
+            let params = gen_parameters(cmd, true, true).join(", ");
+            let args = cmd.params
+                .iter()
+                .map(|param| {
+                    let (ident, ty) = (&*param.ident, &*param.ty);
+                    if ty.contains('*') || ty == "types::GLsync" {
+                        format!("Arg::Ptr({} as usize)", ident)
+                    } else if ty == "types::GLfloat" || ty == "types::GLclampf" {
+                        format!("Arg::Float({} as f64)", ident)
+                    } else {
+                        format!("Arg::Int({} as i64)", ident)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            let ty = &*cmd.proto.ty;
+            if ty == "()" {
+                writeln!(dest, "pub extern \"system\" fn {}({}) {{", name, rust_ty(&params))?;
+            } else {
+                writeln!(dest,
+                         "pub extern \"system\" fn {}({}) -> {} {{",
+                         name,
+                         rust_ty(&params),
+                         rust_ty(ty))?;
+            }
+            writeln!(dest, "    record(\"gl{}\", vec![{}], None);", name, args)?;
+            // Functions generating names hand out new ids.
+            let gens_ids = name.starts_with("Gen") && cmd.params.len() == 2 &&
+                           &*cmd.params[1].ty == "*mut types::GLuint";
+            if gens_ids {
+                writeln!(dest, "    unsafe {{")?;
+                writeln!(dest,
+                         "        super::gen_ids({}, {});",
+                         cmd.params[0].ident,
+                         cmd.params[1].ident)?;
+                writeln!(dest, "    }}")?;
+            }
+            if ty.contains("*mut") {
+                writeln!(dest, "    ptr::null_mut()")?;
+            } else if ty.contains("*const") || ty == "types::GLsync" {
+                writeln!(dest, "    ptr::null()")?;
+            } else if ty != "()" {
+                writeln!(dest, "    0")?;
+            }
+            writeln!(dest, "}}\n")?;
+        }
+
+        writeln!(dest, "/// Gets the stub of an OpenGL function, or null for unknown names.")?;
+        writeln!(dest, "pub fn lookup(name: &str) -> *const c_void {{")?;
+        writeln!(dest, "    match name {{")?;
+        for cmd in registry.cmds.iter() {
+            let name = &*cmd.proto.ident;
+            let module = if MOCKED.contains(&name) { "super::" } else { "" };
+            writeln!(dest,
+                     "        \"gl{}\" => {}{} as *const c_void,",
+                     name,
+                     module,
+                     name)?;
+        }
+        writeln!(dest, "        _ => ptr::null(),")?;
+        writeln!(dest, "    }}")?;
+        writeln!(dest, "}}")
+    }
+}
+
 fn main() {
     let dest = env::var("OUT_DIR").unwrap();
+    let registry = || Registry::new(Api::Gles2, (3, 1), Profile::Compatibility, Fallbacks::All, []);
+
     let mut file = File::create(&Path::new(&dest).join("gl.rs")).unwrap();
+    registry().write_bindings(GlobalGenerator, &mut file).unwrap();
+
+    let mut file = File::create(&Path::new(&dest).join("checked_gl.rs")).unwrap();
+    registry().write_bindings(CheckedGenerator, &mut file).unwrap();
 
-    Registry::new(Api::Gles2, (3, 1), Profile::Compatibility, Fallbacks::All, [])
-        .write_bindings(GlobalGenerator, &mut file)
-        .unwrap();
+    let mut file = File::create(&Path::new(&dest).join("stubs.rs")).unwrap();
+    registry().write_bindings(StubGenerator, &mut file).unwrap();
 }
@@ -1,7 +1,25 @@
 //! Shaders for colored rendering.
 
-/// Vertex shader for GLSL 1.20
+/// Vertex shader for GLSL ES 1.00
 pub const VERTEX_GLSL_120: &'static [u8] = include_bytes!("120.glslv");
 
-/// Fragment shader for GLSL 1.20
+/// Fragment shader for GLSL ES 1.00
 pub const FRAGMENT_GLSL_120: &'static [u8] = include_bytes!("120.glslf");
+
+/// Vertex shader for GLSL ES 3.00
+pub const VERTEX_GLSL_300_ES: &'static [u8] = include_bytes!("300es.glslv");
+
+/// Fragment shader for GLSL ES 3.00
+pub const FRAGMENT_GLSL_300_ES: &'static [u8] = include_bytes!("300es.glslf");
+
+/// Vertex shader for GLSL 1.50, for desktop OpenGL 3.2 core profiles
+pub const VERTEX_GLSL_150: &'static [u8] = include_bytes!("150.glslv");
+
+/// Fragment shader for GLSL 1.50, for desktop OpenGL 3.2 core profiles
+pub const FRAGMENT_GLSL_150: &'static [u8] = include_bytes!("150.glslf");
+
+/// Vertex shader for GLSL 3.30, for desktop OpenGL 3.3 core profiles
+pub const VERTEX_GLSL_330: &'static [u8] = include_bytes!("330.glslv");
+
+/// Fragment shader for GLSL 3.30, for desktop OpenGL 3.3 core profiles
+pub const FRAGMENT_GLSL_330: &'static [u8] = include_bytes!("330.glslf");
@@ -1,111 +1,118 @@
 //! OpenGL back-end for Piston-Graphics.
 
 // External crates.
-use std::ffi::CString;
-use shader_version::{OpenGL, Shaders};
-use shader_version::glsl::GLSL;
+use std::mem;
+use std::os::raw::c_void;
+use std::rc::Rc;
+use shader_version::OpenGL;
 use graphics::{Context, DrawState, Graphics, Viewport};
-use graphics::color::gamma_srgb_to_linear;
+use graphics::math::{multiply, transform_pos, Matrix2d};
+use graphics::triangulation::with_polygon_tri_list;
 use graphics::BACK_END_MAX_VERTEX_COUNT as BUFFER_SIZE;
-use crate::gl;
-use crate::gl::types::{GLint, GLsizei, GLuint};
+use crate::checked_gl as gl;
+use crate::gl::types::{GLenum, GLint, GLsizei, GLuint};
 
 // Local crate.
-use crate::draw_state;
-use crate::Texture;
-use crate::shader_utils::{compile_shader, DynamicAttribute};
+use crate::color_space::{self, ColorSpace};
+use crate::context;
+use crate::draw_state::{self, ClipStencil, CustomBlend};
+use crate::{AlphaMode, RenderTarget, Texture};
+use crate::render_target::{read_rgba, recreate_render_targets};
+use crate::capabilities::{self, Capabilities};
+use crate::texture::{self, recreate_textures, TextureHandle};
+use crate::stats::{FrameStats, GpuTimer, QueryFunctions};
+use image::RgbaImage;
+use crate::shader_utils::{DynamicAttribute, ShaderProgram, ShaderVariant};
+use crate::error::Error;
 
 // The number of chunks to fill up before rendering.
-// Amount of memory used: `BUFFER_SIZE * CHUNKS * 4 * (2 + 4)`
-// `4` for bytes per f32, and `2 + 4` for position and color.
+// Amount of memory used: `BUFFER_SIZE * CHUNKS * 4 * (2 + 4 + 2 + 2)`
+// `4` for bytes per f32, `2 + 4` for position and color of colored vertices
+// and `2 + 2` for position and texture coordinates of textured vertices.
 const CHUNKS: usize = 100;
 
+// Desktop OpenGL, missing from the OpenGL ES bindings.
+const FRAMEBUFFER_SRGB: GLenum = 0x8DB9;
+
+// Mirrors normalized device coordinates vertically.
+const FLIP_Y: Matrix2d = [[1.0, 0.0, 0.0], [0.0, -1.0, 0.0]];
+
 struct Colored {
-    vao: GLuint,
-    vertex_shader: GLuint,
-    fragment_shader: GLuint,
-    program: GLuint,
+    // `None` without vertex array objects, as on many OpenGL ES 2.0 devices.
+    vao: Option<GLuint>,
+    program: ShaderProgram,
     pos: DynamicAttribute,
     color: DynamicAttribute,
     pos_buffer: Vec<[f32; 2]>,
     color_buffer: Vec<[f32; 4]>,
     offset: usize,
+    // The context generation the objects belong to.
+    generation: u32,
 }
 
 impl Drop for Colored {
     fn drop(&mut self) {
+        if !context::is_current(self.generation) {
+            return;
+        }
         unsafe {
-            gl::DeleteVertexArrays(1, &self.vao);
-            gl::DeleteProgram(self.program);
-            gl::DeleteShader(self.vertex_shader);
-            gl::DeleteShader(self.fragment_shader);
+            if let Some(ref vao) = self.vao {
+                gl::DeleteVertexArrays(1, vao);
+            }
         }
     }
 }
 
 impl Colored {
-    fn new(glsl: GLSL) -> Self {
+    fn new(variant: ShaderVariant, vertex_array_objects: bool) -> Result<Self, Error> {
         use shaders::colored;
 
+        let (vertex, fragment) = match variant {
+            ShaderVariant::Es100 => (colored::VERTEX_GLSL_120, colored::FRAGMENT_GLSL_120),
+            ShaderVariant::Es300 => (colored::VERTEX_GLSL_300_ES, colored::FRAGMENT_GLSL_300_ES),
+            ShaderVariant::Glsl150 => (colored::VERTEX_GLSL_150, colored::FRAGMENT_GLSL_150),
+            ShaderVariant::Glsl330 => (colored::VERTEX_GLSL_330, colored::FRAGMENT_GLSL_330),
+        };
         let src = |bytes| unsafe { ::std::str::from_utf8_unchecked(bytes) };
+        let program = ShaderProgram::new(src(vertex), src(fragment))?;
+        let pos = DynamicAttribute::xy(program.id(), "pos")?;
+        let color = DynamicAttribute::rgba(program.id(), "color")?;
 
-
-        let vertex_shader = match compile_shader(
-            gl::VERTEX_SHADER,                  // shader type
-            Shaders::new().set(GLSL::V1_20, src(colored::VERTEX_GLSL_120))
-                          .get(glsl).unwrap()
-        ) {
-            Ok(id) => id,
-            Err(s) => panic!("compile_shader: {}", s),
-        };
-        let fragment_shader = match compile_shader(
-            gl::FRAGMENT_SHADER,                // shader type
-            Shaders::new().set(GLSL::V1_20, src(colored::FRAGMENT_GLSL_120))
-                          .get(glsl).unwrap()
-        ) {
-            Ok(id) => id,
-            Err(s) => panic!("compile_shader: {}", s),
+        let vao = if vertex_array_objects {
+            let mut vao = 0;
+            unsafe {
+                gl::GenVertexArrays(1, &mut vao);
+            }
+            Some(vao)
+        } else {
+            None
         };
-
-        let program;
-        unsafe {
-            program = gl::CreateProgram();
-            gl::AttachShader(program, vertex_shader);
-            gl::AttachShader(program, fragment_shader);
-        }
-
-        let mut vao = 0;
-        unsafe {
-            gl::GenVertexArrays(1, &mut vao);
-            gl::LinkProgram(program);
-        }
-        let pos = DynamicAttribute::xy(program, "pos").unwrap();
-        let color = DynamicAttribute::rgba(program, "color").unwrap();
-        Colored {
+        Ok(Colored {
             vao: vao,
-            vertex_shader: vertex_shader,
-            fragment_shader: fragment_shader,
             program: program,
             pos: pos,
             color: color,
             pos_buffer: vec![[0.0; 2]; CHUNKS * BUFFER_SIZE],
             color_buffer: vec![[0.0; 4]; CHUNKS * BUFFER_SIZE],
             offset: 0,
-        }
+            generation: context::generation(),
+        })
     }
 
-    fn flush(&mut self) {
+    fn flush(&mut self, stats: &mut FrameStats) {
+        stats.draw_calls += 1;
+        stats.vertices += self.offset as u32;
+        stats.bytes_uploaded += self.offset * (2 + 4) * 4;
         unsafe {
-            gl::BindVertexArray(self.vao);
             // Render triangles whether they are facing
             // clockwise or counter clockwise.
             gl::Disable(gl::CULL_FACE);
-            self.color.bind_vao(self.vao);
+            bind_attribute(&self.color, self.vao);
             self.color.set(&self.color_buffer[..self.offset]);
-            self.pos.bind_vao(self.vao);
+            bind_attribute(&self.pos, self.vao);
             self.pos.set(&self.pos_buffer[..self.offset]);
             gl::DrawArrays(gl::TRIANGLES, 0, self.offset as i32);
-            gl::BindVertexArray(0);
+            unbind_attributes(&[&self.color, &self.pos], self.vao);
         }
 
         self.offset = 0;
@@ -113,81 +120,173 @@ impl Colored {
 }
 
 struct Textured {
-    vertex_shader: GLuint,
-    fragment_shader: GLuint,
-    program: GLuint,
-    vao: GLuint,
-    color: GLint,
+    // `None` without vertex array objects, as on many OpenGL ES 2.0 devices.
+    vao: Option<GLuint>,
+    program: ShaderProgram,
     pos: DynamicAttribute,
     uv: DynamicAttribute,
+    pos_buffer: Vec<[f32; 2]>,
+    uv_buffer: Vec<[f32; 2]>,
+    offset: usize,
+    // The texture and color shared by the buffered vertices.
+    // The handle keeps the texture alive until the vertices are flushed.
+    last_texture: Option<TextureHandle>,
+    last_coverage: [f32; 4],
+    last_color: [f32; 4],
+    last_premultiplied: bool,
+    // The context generation the objects belong to.
+    generation: u32,
 }
 
 impl Drop for Textured {
     fn drop(&mut self) {
+        if !context::is_current(self.generation) {
+            return;
+        }
         unsafe {
-            gl::DeleteVertexArrays(1, &self.vao);
-            gl::DeleteProgram(self.program);
-            gl::DeleteShader(self.vertex_shader);
-            gl::DeleteShader(self.fragment_shader);
+            if let Some(ref vao) = self.vao {
+                gl::DeleteVertexArrays(1, vao);
+            }
         }
     }
 }
 
 impl Textured {
-    fn new(glsl: GLSL) -> Self {
+    fn new(variant: ShaderVariant, vertex_array_objects: bool) -> Result<Self, Error> {
         use shaders::textured;
 
-        let src = |bytes| unsafe { ::std::str::from_utf8_unchecked(bytes) };
-
-        let vertex_shader = match compile_shader(
-            gl::VERTEX_SHADER,                  // shader type
-            Shaders::new().set(GLSL::V1_20, src(textured::VERTEX_GLSL_120))
-                          .get(glsl).unwrap()
-        ) {
-            Ok(id) => id,
-            Err(s) => panic!("compile_shader: {}", s),
+        let (vertex, fragment) = match variant {
+            ShaderVariant::Es100 => (textured::VERTEX_GLSL_120, textured::FRAGMENT_GLSL_120),
+            ShaderVariant::Es300 => (textured::VERTEX_GLSL_300_ES, textured::FRAGMENT_GLSL_300_ES),
+            ShaderVariant::Glsl150 => (textured::VERTEX_GLSL_150, textured::FRAGMENT_GLSL_150),
+            ShaderVariant::Glsl330 => (textured::VERTEX_GLSL_330, textured::FRAGMENT_GLSL_330),
         };
-        let fragment_shader = match compile_shader(
-            gl::FRAGMENT_SHADER,                // shader type
-            Shaders::new().set(GLSL::V1_20, src(textured::FRAGMENT_GLSL_120))
-                          .get(glsl).unwrap()
-        ) {
-            Ok(id) => id,
-            Err(s) => panic!("compile_shader: {}", s),
-        };
-
-        let program;
-        unsafe {
-            program = gl::CreateProgram();
-            gl::AttachShader(program, vertex_shader);
-            gl::AttachShader(program, fragment_shader);
+        let src = |bytes| unsafe { ::std::str::from_utf8_unchecked(bytes) };
+        let program = ShaderProgram::new(src(vertex), src(fragment))?;
+        let pos = DynamicAttribute::xy(program.id(), "pos")?;
+        let uv = DynamicAttribute::uv(program.id(), "uv")?;
+        // Looks up the uniforms now, so setting them when flushing does not fail.
+        for name in &["color", "coverage", "premultiplied"] {
+            program.uniform_location(name)?;
         }
 
-        let mut vao = 0;
-        unsafe {
-            gl::GenVertexArrays(1, &mut vao);
-            gl::LinkProgram(program);
-        }
-        let pos = DynamicAttribute::xy(program, "pos").unwrap();
-        let c_color = CString::new("color").unwrap();
-        let color = unsafe {   gl::GetUniformLocation(program, c_color.as_ptr()) };
-        drop(c_color);
-        if color == -1 {
-            panic!("Could not find uniform `color`");
-        }
-        let uv = DynamicAttribute::uv(program, "uv").unwrap();
-        Textured {
+        let vao = if vertex_array_objects {
+            let mut vao = 0;
+            unsafe {
+                gl::GenVertexArrays(1, &mut vao);
+            }
+            Some(vao)
+        } else {
+            None
+        };
+        Ok(Textured {
             vao: vao,
-            vertex_shader: vertex_shader,
-            fragment_shader: fragment_shader,
             program: program,
             pos: pos,
-            color: color,
             uv: uv,
+            pos_buffer: vec![[0.0; 2]; CHUNKS * BUFFER_SIZE],
+            uv_buffer: vec![[0.0; 2]; CHUNKS * BUFFER_SIZE],
+            offset: 0,
+            generation: context::generation(),
+            last_texture: None,
+            last_coverage: [0.0; 4],
+            last_color: [0.0; 4],
+            last_premultiplied: false,
+        })
+    }
+
+    fn flush(&mut self, stats: &mut FrameStats) {
+        stats.draw_calls += 1;
+        stats.vertices += self.offset as u32;
+        stats.bytes_uploaded += self.offset * (2 + 2) * 4;
+        stats.texture_binds += 1;
+        let color = self.last_color;
+        let coverage = self.last_coverage;
+        unsafe {
+            // Render triangles whether they are facing
+            // clockwise or counter clockwise.
+            gl::Disable(gl::CULL_FACE);
+            gl::BindTexture(gl::TEXTURE_2D, self.last_texture.as_ref().map_or(0, |t| t.id()));
+            // The uniforms were found when creating the program.
+            let _ = self.program.set_vec4("color", color);
+            let _ = self.program.set_vec4("coverage", coverage);
+            let _ = self.program.set_f32("premultiplied",
+                                         if self.last_premultiplied { 1.0 } else { 0.0 });
+            bind_attribute(&self.pos, self.vao);
+            self.pos.set(&self.pos_buffer[..self.offset]);
+            bind_attribute(&self.uv, self.vao);
+            self.uv.set(&self.uv_buffer[..self.offset]);
+            gl::DrawArrays(gl::TRIANGLES, 0, self.offset as i32);
+            unbind_attributes(&[&self.pos, &self.uv], self.vao);
         }
+
+        self.offset = 0;
     }
 }
 
+// Binds the buffer of an attribute, to the vertex array object if any.
+fn bind_attribute(attribute: &DynamicAttribute, vao: Option<GLuint>) {
+    match vao {
+        Some(vao) => attribute.bind_vao(vao),
+        None => attribute.bind(),
+    }
+}
+
+// Restores the vertex attribute state after drawing.
+unsafe fn unbind_attributes(attributes: &[&DynamicAttribute], vao: Option<GLuint>) {
+    match vao {
+        Some(_) => gl::BindVertexArray(0),
+        // Enabled arrays would be read by the next draw with another program.
+        None => {
+            for attribute in attributes {
+                attribute.disable();
+            }
+        }
+    }
+}
+
+// Checks the function pointers used by the back-end.
+// Vertex array objects are optional, as many OpenGL ES 2.0 devices lack them.
+fn check_functions_loaded() -> Result<(), Error> {
+    let functions: [(&'static str, fn() -> bool); 29] = [
+        ("glAttachShader", gl::AttachShader::is_loaded),
+        ("glBindBuffer", gl::BindBuffer::is_loaded),
+        ("glBindTexture", gl::BindTexture::is_loaded),
+        ("glBlendColor", gl::BlendColor::is_loaded),
+        ("glBlendEquationSeparate", gl::BlendEquationSeparate::is_loaded),
+        ("glBlendFuncSeparate", gl::BlendFuncSeparate::is_loaded),
+        ("glBufferData", gl::BufferData::is_loaded),
+        ("glClear", gl::Clear::is_loaded),
+        ("glClearColor", gl::ClearColor::is_loaded),
+        ("glCompileShader", gl::CompileShader::is_loaded),
+        ("glCreateProgram", gl::CreateProgram::is_loaded),
+        ("glCreateShader", gl::CreateShader::is_loaded),
+        ("glDisable", gl::Disable::is_loaded),
+        ("glDrawArrays", gl::DrawArrays::is_loaded),
+        ("glEnable", gl::Enable::is_loaded),
+        ("glEnableVertexAttribArray", gl::EnableVertexAttribArray::is_loaded),
+        ("glGenBuffers", gl::GenBuffers::is_loaded),
+        ("glGetAttribLocation", gl::GetAttribLocation::is_loaded),
+        ("glGetProgramiv", gl::GetProgramiv::is_loaded),
+        ("glGetShaderiv", gl::GetShaderiv::is_loaded),
+        ("glGetUniformLocation", gl::GetUniformLocation::is_loaded),
+        ("glLinkProgram", gl::LinkProgram::is_loaded),
+        ("glScissor", gl::Scissor::is_loaded),
+        ("glShaderSource", gl::ShaderSource::is_loaded),
+        ("glStencilFunc", gl::StencilFunc::is_loaded),
+        ("glStencilOp", gl::StencilOp::is_loaded),
+        ("glUniform4f", gl::Uniform4f::is_loaded),
+        ("glUseProgram", gl::UseProgram::is_loaded),
+        ("glVertexAttribPointer", gl::VertexAttribPointer::is_loaded),
+    ];
+    for &(name, is_loaded) in functions.iter() {
+        if !is_loaded() {
+            return Err(Error::FunctionNotLoaded(name));
+        }
+    }
+    Ok(())
+}
+
 // Newlines and indents for cleaner panic message.
 const GL_FUNC_NOT_LOADED: &'static str = "
     OpenGL function pointers must be loaded before creating the `Gl` backend!
@@ -197,12 +296,52 @@ const GL_FUNC_NOT_LOADED: &'static str = "
 
 /// Contains OpenGL data.
 pub struct GlGraphics {
+    opengl: OpenGL,
+    shader_variant: ShaderVariant,
     colored: Colored,
     textured: Textured,
     // Keeps track of the current shader program.
     current_program: Option<GLuint>,
     // Keeps track of the current draw state.
     current_draw_state: Option<DrawState>,
+    stats: FrameStats,
+    gpu_timer: Option<GpuTimer>,
+    capabilities: Rc<Capabilities>,
+    color_space: ColorSpace,
+    // Replaces the blend of draw states when set.
+    custom_blend: Option<CustomBlend>,
+    // Whether `Blend::Alpha` is bound for premultiplied sources.
+    premultiplied_blend: bool,
+    clip_stack: Vec<Clip>,
+    // The scissor rectangle of the clips.
+    clip_rect: Option<[u32; 4]>,
+    // The number of clips in the stencil buffer.
+    stencil_depth: u8,
+    // Replaces the stencil of draw states when set.
+    clip_stencil: Option<ClipStencil>,
+}
+
+// A clip pushed to the clip stack of `GlGraphics`.
+enum Clip {
+    // A scissor clip, with the scissor rectangle of the clips before.
+    Scissor(Option<[u32; 4]>),
+    // A clip in the stencil buffer.
+    Stencil,
+}
+
+// Intersects two scissor rectangles.
+fn intersect(a: Option<[u32; 4]>, b: Option<[u32; 4]>) -> Option<[u32; 4]> {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            let x = a[0].max(b[0]);
+            let y = a[1].max(b[1]);
+            let right = (a[0] + a[2]).min(b[0] + b[2]).max(x);
+            let top = (a[1] + a[3]).min(b[1] + b[3]).max(y);
+            Some([x, y, right - x, top - y])
+        }
+        (a, None) => a,
+        (None, b) => b,
+    }
 }
 
 impl<'a> GlGraphics {
@@ -214,14 +353,72 @@ impl<'a> GlGraphics {
     pub fn new(opengl: OpenGL) -> Self {
         assert!(gl::Enable::is_loaded(), GL_FUNC_NOT_LOADED);
 
-        let glsl = opengl.to_glsl();
+        match GlGraphics::try_new(opengl) {
+            Ok(g) => g,
+            Err(e) => panic!("GlGraphics::new: {}", e),
+        }
+    }
+
+    /// Creates a new OpenGL back-end, reporting failures instead of panicking.
+    ///
+    /// `opengl` limits the shading language version, see `ShaderVariant::select`.
+    /// Returns `Err` if function pointers are not loaded, no shader variant suits the context,
+    /// or the shaders fail to compile or link.
+    pub fn try_new(opengl: OpenGL) -> Result<Self, Error> {
+        check_functions_loaded()?;
+
+        let capabilities = capabilities::refresh();
+        let vao = capabilities.vertex_array_objects;
+        let variant = ShaderVariant::select(opengl, &capabilities)?;
         // Load the vertices, color and texture coord buffers.
-        GlGraphics {
-            colored: Colored::new(glsl),
-            textured: Textured::new(glsl),
+        let mut g = GlGraphics {
+            opengl: opengl,
+            shader_variant: variant,
+            colored: Colored::new(variant, vao)?,
+            textured: Textured::new(variant, vao)?,
             current_program: None,
             current_draw_state: None,
+            stats: FrameStats::default(),
+            gpu_timer: None,
+            capabilities: capabilities,
+            color_space: ColorSpace::default(),
+            custom_blend: None,
+            premultiplied_blend: false,
+            clip_stack: Vec::new(),
+            clip_rect: None,
+            stencil_depth: 0,
+            clip_stencil: None,
+        };
+        g.set_color_space(ColorSpace::default());
+        Ok(g)
+    }
+
+    /// Recreates all OpenGL objects after the context was lost and a new one was made current,
+    /// as happens when an Android app is paused and resumed.
+    ///
+    /// Shader programs and vertex buffers are rebuilt, and every `Texture` and `RenderTarget`
+    /// is recreated in place, so existing handles stay valid.
+    /// Textures are created again with their reload function, such as those from `from_path`,
+    /// or from a copy of their pixel data, see `set_keep_texture_pixels`.
+    /// Other textures keep their stale id. The contents of render targets are cleared.
+    ///
+    /// Ids of the lost context are never deleted, since the new context may reuse them.
+    pub fn recreate_after_context_loss(&mut self) -> Result<(), Error> {
+        context::context_lost();
+        self.capabilities = capabilities::refresh();
+        let vao = self.capabilities.vertex_array_objects;
+        self.shader_variant = ShaderVariant::select(self.opengl, &self.capabilities)?;
+        self.colored = Colored::new(self.shader_variant, vao)?;
+        self.textured = Textured::new(self.shader_variant, vao)?;
+        self.current_program = None;
+        self.current_draw_state = None;
+        let color_space = self.color_space;
+        self.set_color_space(color_space);
+        if let Some(functions) = self.gpu_timer.as_ref().map(|timer| timer.functions()) {
+            self.gpu_timer = Some(GpuTimer::new(functions));
         }
+        recreate_textures()?;
+        recreate_render_targets()
     }
 
     /// Sets viewport with normalized coordinates and center as origin.
@@ -246,6 +443,7 @@ impl<'a> GlGraphics {
             gl::UseProgram(program);
         }
         self.current_program = Some(program);
+        self.stats.program_switches += 1;
     }
 
     /// Unset the current program.
@@ -256,18 +454,176 @@ impl<'a> GlGraphics {
     }
 
     /// Sets the current draw state, by detecting changes.
+    ///
+    /// While clips are pushed, the scissor rectangle is limited to the clips
+    /// and the stencil of the draw state is ignored.
     pub fn use_draw_state(&mut self, draw_state: &DrawState) {
+        let new_state = self.clipped(draw_state);
         match self.current_draw_state {
             None => {
-                draw_state::bind_scissor(draw_state.scissor);
-                draw_state::bind_stencil(draw_state.stencil);
-                draw_state::bind_blend(draw_state.blend);
+                draw_state::bind_scissor(new_state.scissor);
+                draw_state::bind_stencil(new_state.stencil);
+                match self.clip_stencil {
+                    Some(clip) => draw_state::bind_clip_stencil(clip),
+                    // Clips may have disabled color writes.
+                    None => unsafe { gl::ColorMask(gl::TRUE, gl::TRUE, gl::TRUE, gl::TRUE) },
+                }
+                match self.custom_blend {
+                    Some(ref blend) => draw_state::bind_custom_blend(blend),
+                    None => draw_state::bind_blend(new_state.blend, self.premultiplied_blend),
+                }
             }
             Some(ref old_state) => {
-                draw_state::bind_state(old_state, draw_state);
+                draw_state::bind_state(&self.clipped(old_state),
+                                       &new_state,
+                                       self.custom_blend.is_some(),
+                                       self.premultiplied_blend);
             }
         }
         self.current_draw_state = Some(*draw_state);
+        self.stats.draw_state_changes += 1;
+    }
+
+    // Applies the clips to a draw state.
+    fn clipped(&self, draw_state: &DrawState) -> DrawState {
+        let mut draw_state = *draw_state;
+        draw_state.scissor = intersect(self.clip_rect, draw_state.scissor);
+        if self.clip_stencil.is_some() {
+            draw_state.stencil = None;
+        }
+        draw_state
+    }
+
+    /// Limits drawing to a convex polygon, transformed by `transform`,
+    /// until `pop_clip` is called.
+    ///
+    /// Clips nest, drawing only where all pushed clips overlap.
+    /// Axis-aligned rectangles clip with the scissor test, other shapes with the stencil buffer,
+    /// which must be cleared to 0 with `clear_stencil` before pushing the first one.
+    /// Returns `Err` for shapes other than rectangles if the bound framebuffer has no stencil
+    /// buffer, as for render targets from `RenderTarget::new`, or when more of them are pushed
+    /// than its stencil bits can count, 255 for 8 bits.
+    pub fn push_clip(&mut self, transform: Matrix2d, polygon: &[[f64; 2]]) -> Result<(), Error> {
+        // Pending vertices are drawn with the clips before.
+        self.flush_colored();
+        self.flush_textured();
+        if let Some(rect) = self.scissor_rect(transform, polygon) {
+            self.clip_stack.push(Clip::Scissor(self.clip_rect));
+            self.clip_rect = intersect(self.clip_rect, Some(rect));
+            self.current_draw_state = None;
+            return Ok(());
+        }
+        let stencil_bits = self.capabilities.bound_stencil_bits();
+        if stencil_bits == 0 {
+            return Err(Error::Unsupported("Clipping to shapes other than rectangles requires \
+                                           a stencil buffer"
+                .to_string()));
+        }
+        let max_depth = (1u32 << stencil_bits.min(8)) - 1;
+        if self.stencil_depth as u32 >= max_depth {
+            return Err(Error::Unsupported(format!("The stencil buffer holds up to {} clips",
+                                                  max_depth)));
+        }
+
+        let depth = self.stencil_depth;
+        self.draw_clip_stencil(ClipStencil::Push(depth), |f| {
+            with_polygon_tri_list(transform, polygon, |vertices| f(vertices))
+        });
+        self.clip_stack.push(Clip::Stencil);
+        self.stencil_depth += 1;
+        self.clip_stencil = Some(ClipStencil::Inside(self.stencil_depth));
+        self.current_draw_state = None;
+        Ok(())
+    }
+
+    /// Limits drawing to a rectangle `[x, y, w, h]`, transformed by `transform`,
+    /// until `pop_clip` is called.
+    ///
+    /// This is `push_clip` with the corners of the rectangle.
+    pub fn push_clip_rect(&mut self, transform: Matrix2d, rect: [f64; 4]) -> Result<(), Error> {
+        let (x, y, w, h) = (rect[0], rect[1], rect[2], rect[3]);
+        self.push_clip(transform, &[[x, y], [x + w, y], [x + w, y + h], [x, y + h]])
+    }
+
+    /// Removes the last pushed clip.
+    ///
+    /// # Panics
+    /// If no clip was pushed.
+    pub fn pop_clip(&mut self) {
+        self.flush_colored();
+        self.flush_textured();
+        match self.clip_stack.pop().expect("pop_clip called without a pushed clip") {
+            Clip::Scissor(rect) => self.clip_rect = rect,
+            Clip::Stencil => {
+                self.stencil_depth -= 1;
+                let depth = self.stencil_depth;
+                // Covers the viewport, which is within -1 to 1 after transforming.
+                self.draw_clip_stencil(ClipStencil::Pop(depth), |f| {
+                    f(&[[-1.0, -1.0], [1.0, -1.0], [1.0, 1.0],
+                        [-1.0, -1.0], [1.0, 1.0], [-1.0, 1.0]])
+                });
+                self.clip_stencil = if depth > 0 {
+                    Some(ClipStencil::Inside(depth))
+                } else {
+                    None
+                };
+            }
+        }
+        self.current_draw_state = None;
+    }
+
+    /// Returns the number of pushed clips.
+    pub fn clip_depth(&self) -> usize {
+        self.clip_stack.len()
+    }
+
+    // Draws triangles into the stencil buffer of the clips.
+    fn draw_clip_stencil<F>(&mut self, clip: ClipStencil, f: F)
+        where F: FnMut(&mut FnMut(&[[f32; 2]]))
+    {
+        self.clip_stencil = Some(clip);
+        self.current_draw_state = None;
+        self.tri_list(&DrawState::default(), &[1.0; 4], f);
+        self.flush_colored();
+    }
+
+    // Gets the scissor rectangle of a polygon, if it is an axis-aligned rectangle.
+    fn scissor_rect(&self, transform: Matrix2d, polygon: &[[f64; 2]]) -> Option<[u32; 4]> {
+        if polygon.len() != 4 {
+            return None;
+        }
+        let mut viewport = [0; 4];
+        unsafe {
+            gl::GetIntegerv(gl::VIEWPORT, viewport.as_mut_ptr());
+        }
+        // Window coordinates of the corners.
+        let corners: Vec<[f64; 2]> = polygon.iter()
+            .map(|p| {
+                let p = transform_pos(transform, *p);
+                [viewport[0] as f64 + (p[0] + 1.0) / 2.0 * viewport[2] as f64,
+                 viewport[1] as f64 + (p[1] + 1.0) / 2.0 * viewport[3] as f64]
+            })
+            .collect();
+        // Each edge must be horizontal or vertical, alternating.
+        let horizontal = corners[0][1] == corners[1][1];
+        for i in 0..4 {
+            let (a, b) = (corners[i], corners[(i + 1) % 4]);
+            let aligned = if (i % 2 == 0) == horizontal {
+                a[1] == b[1]
+            } else {
+                a[0] == b[0]
+            };
+            if !aligned {
+                return None;
+            }
+        }
+        let min = |axis: usize| corners.iter().map(|c| c[axis]).fold(f64::INFINITY, f64::min);
+        let max = |axis: usize| corners.iter().map(|c| c[axis]).fold(f64::NEG_INFINITY, f64::max);
+        // Pixels are inside when their center is, as when rasterizing the polygon.
+        let pixel = |x: f64| x.round().max(0.0) as u32;
+        let (left, bottom) = (pixel(min(0)), pixel(min(1)));
+        let (right, top) = (pixel(max(0)).max(left), pixel(max(1)).max(bottom));
+        Some([left, bottom, right - left, top - bottom])
     }
 
     /// Unsets the current draw state.
@@ -277,22 +633,280 @@ impl<'a> GlGraphics {
         self.current_draw_state = None;
     }
 
+    /// Gets what the context supports, as queried when creating the back-end
+    /// or recreating it after the context was lost.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Gets the shading language version of the built-in shaders.
+    pub fn shader_variant(&self) -> ShaderVariant {
+        self.shader_variant
+    }
+
+    /// Gets the color space colors are blended in.
+    pub fn get_color_space(&self) -> ColorSpace {
+        self.color_space
+    }
+
+    /// Sets the color space colors are blended in, `ColorSpace::Srgb` by default.
+    ///
+    /// This applies to clears and vertex colors from now on, and to textures
+    /// and render targets created on this thread afterwards.
+    /// Existing textures keep the format they were created with.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) {
+        self.color_space = color_space;
+        color_space::set_current(color_space);
+        // Desktop OpenGL only writes to sRGB framebuffers with conversion when enabled.
+        if !self.capabilities.es {
+            unsafe {
+                match color_space {
+                    ColorSpace::Linear => gl::Enable(FRAMEBUFFER_SRGB),
+                    ColorSpace::Srgb => gl::Disable(FRAMEBUFFER_SRGB),
+                }
+            }
+        }
+    }
+
+    /// Sets whether textures created on this thread from now on keep a copy of their
+    /// pixel data, to be recreated from by `recreate_after_context_loss`. Off by default.
+    ///
+    /// Only enable this on platforms losing the context, as it doubles the memory
+    /// used by textures without a reload function.
+    pub fn set_keep_texture_pixels(&mut self, keep: bool) {
+        texture::set_keep_pixels(keep);
+    }
+
+    /// Gets the blend replacing the blend of draw states, if any.
+    pub fn get_custom_blend(&self) -> Option<CustomBlend> {
+        self.custom_blend
+    }
+
+    /// Sets a blend that replaces the blend of draw states, until it is set to `None`.
+    ///
+    /// Triangles drawn before are rendered with the previous blend.
+    /// Returns `Err` if the blend uses `Min` or `Max`, which the context does not support.
+    pub fn set_custom_blend(&mut self, blend: Option<CustomBlend>) -> Result<(), Error> {
+        if let Some(ref blend) = blend {
+            if blend.uses_min_max() && !self.capabilities.has_es3_features() &&
+               !self.capabilities.has_extension("GL_EXT_blend_minmax") {
+                return Err(Error::Unsupported("Min and max blending require OpenGL ES 3.0 \
+                                               or EXT_blend_minmax"
+                    .to_string()));
+            }
+        }
+        self.flush_colored();
+        self.flush_textured();
+        self.custom_blend = blend;
+        // Binds the new blend with the next draw state.
+        self.current_draw_state = None;
+        Ok(())
+    }
+
+    /// Gets the counters of the frame drawn by the last or current call to `draw`.
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Enables measuring the GPU time of each `draw` call, reported by `stats`.
+    ///
+    /// `load` resolves OpenGL function names, like the function passed to `gl::load_with`,
+    /// and is used to load the query functions of `EXT_disjoint_timer_query`.
+    /// Returns `Err` if the context lacks the extension.
+    pub fn enable_gpu_timing<F>(&mut self, load: F) -> Result<(), Error>
+        where F: FnMut(&str) -> *const c_void
+    {
+        let functions = QueryFunctions::load(load)?;
+        self.gpu_timer = Some(GpuTimer::new(functions));
+        Ok(())
+    }
+
+    /// Stops measuring the GPU time of `draw` calls.
+    pub fn disable_gpu_timing(&mut self) {
+        self.gpu_timer = None;
+    }
+
     /// Draws graphics.
     pub fn draw<F, U>(&mut self, viewport: Viewport, f: F) -> U
         where F: FnOnce(Context, &mut Self) -> U
     {
+        let gpu_time_ns = self.gpu_timer.as_mut().and_then(|timer| timer.begin());
+        self.stats = FrameStats {
+            gpu_time_ns: gpu_time_ns,
+            ..FrameStats::default()
+        };
         let rect = viewport.rect;
         let (x, y, w, h) = (rect[0], rect[1], rect[2], rect[3]);
         self.viewport(x, y, w, h);
         self.clear_program();
         let c = Context::new_viewport(viewport);
         let res = f(c, self);
+        self.flush_colored();
+        self.flush_textured();
+        if let Some(ref mut timer) = self.gpu_timer {
+            timer.end();
+        }
+        res
+    }
+
+    /// Draws graphics into a render target.
+    ///
+    /// Drawing is flipped vertically for targets created by `RenderTarget::new`,
+    /// so their texture can be drawn upright with `graphics::Image`.
+    /// The previous framebuffer and viewport are restored afterwards.
+    /// Pushed clips belong to the previous framebuffer, so drawing into the target
+    /// starts without clips, and they apply again afterwards.
+    pub fn draw_to<F, U>(&mut self, target: &mut RenderTarget, viewport: Viewport, f: F) -> U
+        where F: FnOnce(Context, &mut Self) -> U
+    {
+        // Pending vertices belong to the previous framebuffer.
+        self.flush_colored();
+        self.flush_textured();
+        let clip_stack = mem::take(&mut self.clip_stack);
+        let clip_rect = self.clip_rect.take();
+        let stencil_depth = mem::take(&mut self.stencil_depth);
+        let clip_stencil = self.clip_stencil.take();
+        // Binds the scissor and stencil without the clips.
+        self.current_draw_state = None;
+
+        let mut previous_fbo = 0;
+        let mut previous_viewport = [0; 4];
+        unsafe {
+            gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut previous_fbo);
+            gl::GetIntegerv(gl::VIEWPORT, previous_viewport.as_mut_ptr());
+            gl::BindFramebuffer(gl::FRAMEBUFFER, target.get_fbo());
+        }
+
+        let rect = viewport.rect;
+        let (x, y, w, h) = (rect[0], rect[1], rect[2], rect[3]);
+        self.viewport(x, y, w, h);
+        self.clear_program();
+        let mut c = Context::new_viewport(viewport);
+        if target.flip_y() {
+            c.view = multiply(FLIP_Y, c.view);
+            c.transform = multiply(FLIP_Y, c.transform);
+        }
+        let res = f(c, self);
+        self.flush_colored();
+        self.flush_textured();
+
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, previous_fbo as GLuint);
+        }
+        let v = previous_viewport;
+        self.viewport(v[0], v[1], v[2], v[3]);
+        // Clips left pushed inside the target are dropped.
+        self.clip_stack = clip_stack;
+        self.clip_rect = clip_rect;
+        self.stencil_depth = stencil_depth;
+        self.clip_stencil = clip_stencil;
+        self.current_draw_state = None;
+        res
+    }
+
+    /// Reads back a rectangle `[x, y, w, h]` of the bound framebuffer.
+    ///
+    /// As with `glReadPixels`, `x` and `y` are the lower-left corner of the rectangle.
+    /// The returned image has its first row at the top.
+    /// Render targets are always RGBA, so this works for any of them, unlike
+    /// `Texture::to_image` for alpha and luminance textures.
+    pub fn read_pixels(&mut self, rect: [u32; 4]) -> RgbaImage {
+        self.flush_colored();
+        self.flush_textured();
+
+        let (w, h) = (rect[2], rect[3]);
+        let pixels = read_rgba(rect[0] as i32, rect[1] as i32, w, h);
+        let row = (w * 4) as usize;
+        let mut flipped = Vec::with_capacity(pixels.len());
+        if row > 0 {
+            for line in pixels.chunks(row).rev() {
+                flipped.extend_from_slice(line);
+            }
+        }
+        RgbaImage::from_raw(w, h, flipped).unwrap()
+    }
+
+    /// Reads back the contents of a render target, such as after `draw_to`.
+    ///
+    /// The returned image has its first row at the top,
+    /// as drawn with the `Context` passed by `draw_to`.
+    pub fn read_target(&mut self, target: &RenderTarget) -> RgbaImage {
+        // Pending vertices belong to the bound framebuffer.
+        self.flush_colored();
+        self.flush_textured();
+
+        let (w, h) = target.get_size();
+        let mut previous_fbo = 0;
+        let pixels = unsafe {
+            gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut previous_fbo);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, target.get_fbo());
+            let pixels = read_rgba(0, 0, w, h);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, previous_fbo as GLuint);
+            pixels
+        };
+        // Flipped targets already store the first row at the top.
+        let row = (w * 4) as usize;
+        let pixels = if target.flip_y() || row == 0 {
+            pixels
+        } else {
+            pixels.chunks(row).rev().flat_map(|line| line.iter().cloned()).collect()
+        };
+        RgbaImage::from_raw(w, h, pixels).unwrap()
+    }
+
+    /// Renders the batched vertices, if any.
+    ///
+    /// Draws are batched until the texture or state changes, and drawn with the textures
+    /// as they are then. Flush before updating a texture drawn since the last flush,
+    /// so the earlier draws keep the old contents.
+    pub fn flush(&mut self) {
+        self.flush_colored();
+        self.flush_textured();
+    }
+
+    /// Renders the buffered colored vertices, if any.
+    fn flush_colored(&mut self) {
         if self.colored.offset > 0 {
-            let program = self.colored.program;
+            let program = self.colored.program.id();
             self.use_program(program);
-            self.colored.flush();
+            self.colored.flush(&mut self.stats);
+        }
+    }
+
+    /// Renders the buffered textured vertices, if any.
+    fn flush_textured(&mut self) {
+        if self.textured.offset > 0 {
+            let program = self.textured.program.id();
+            self.use_program(program);
+            self.textured.flush(&mut self.stats);
+            // Let dropped textures be deleted.
+            self.textured.last_texture = None;
+        }
+    }
+
+    /// Selects blending for straight or premultiplied sources,
+    /// which is bound with the next draw state.
+    fn use_premultiplied_blend(&mut self, premultiplied: bool) {
+        if self.premultiplied_blend != premultiplied {
+            self.premultiplied_blend = premultiplied;
+            self.current_draw_state = None;
+        }
+    }
+
+    /// Renders the buffered colored vertices before changing state.
+    fn flush_colored_on_change(&mut self) {
+        if self.colored.offset > 0 {
+            self.stats.state_change_flushes += 1;
+            self.flush_colored();
+        }
+    }
+
+    /// Renders the buffered textured vertices before changing state.
+    fn flush_textured_on_change(&mut self) {
+        if self.textured.offset > 0 {
+            self.stats.state_change_flushes += 1;
+            self.flush_textured();
         }
-        res
     }
 
     /// Assume all textures has alpha channel for now.
@@ -305,7 +919,10 @@ impl Graphics for GlGraphics {
     type Texture = Texture;
 
     fn clear_color(&mut self, color: [f32; 4]) {
-        let color = gamma_srgb_to_linear(color);
+        // Pending vertices are drawn before the clear.
+        self.flush_colored();
+        self.flush_textured();
+        let color = self.color_space.convert(color);
         unsafe {
             let (r, g, b, a) = (color[0], color[1], color[2], color[3]);
             gl::ClearColor(r, g, b, a);
@@ -314,35 +931,45 @@ impl Graphics for GlGraphics {
     }
 
     fn clear_stencil(&mut self, value: u8) {
+        // Pending vertices may test against the stencil before the clear.
+        self.flush_colored();
+        self.flush_textured();
         unsafe {
             gl::ClearStencil(value as i32);
+            // Clearing is limited by the stencil write mask.
+            gl::StencilMask(255);
+            gl::Clear(gl::STENCIL_BUFFER_BIT);
         }
     }
 
     fn tri_list<F>(&mut self, draw_state: &DrawState, color: &[f32; 4], mut f: F)
         where F: FnMut(&mut FnMut(&[[f32; 2]]))
     {
-        let color = gamma_srgb_to_linear(*color);
+        let color = self.color_space.convert(*color);
+
+        // Keep the drawing order with textured triangles.
+        self.flush_textured_on_change();
+        self.use_premultiplied_blend(false);
 
         // Flush when draw state changes.
-        if self.current_draw_state.is_none() ||
-           self.current_draw_state.as_ref().unwrap() != draw_state {
-            let program = self.colored.program;
-            self.use_program(program);
-            if self.current_draw_state.is_none() {
-                self.use_draw_state(&Default::default());
-            }
-            self.colored.flush();
+        if self.current_draw_state.as_ref() != Some(draw_state) {
+            self.flush_colored_on_change();
             self.use_draw_state(draw_state);
         }
 
+        // Overflowing the buffer flushes with the current program.
+        let program = self.colored.program.id();
+        self.use_program(program);
+
         let ref mut shader = self.colored;
+        let ref mut stats = self.stats;
         f(&mut |vertices: &[[f32; 2]]| {
             let items = vertices.len();
 
             // Render if there is not enough room.
             if shader.offset + items > BUFFER_SIZE * CHUNKS {
-                shader.flush();
+                stats.overflow_flushes += 1;
+                shader.flush(stats);
             }
 
             for i in 0..items {
@@ -362,51 +989,365 @@ impl Graphics for GlGraphics {
                       mut f: F)
         where F: FnMut(&mut FnMut(&[[f32; 2]], &[[f32; 2]]))
     {
-        let color = gamma_srgb_to_linear(*color);
-
-        if self.colored.offset > 0 {
-            let program = self.colored.program;
-            self.use_program(program);
-            self.colored.flush();
+        let mut color = self.color_space.convert(*color);
+        let premultiplied = texture.get_alpha_mode() == AlphaMode::Premultiplied;
+        if premultiplied {
+            color = [color[0] * color[3], color[1] * color[3], color[2] * color[3], color[3]];
+        }
+        let mut coverage = [0.0; 4];
+        if let Some(channel) = texture.get_format().coverage_channel() {
+            coverage[channel] = 1.0;
         }
+        texture.refresh_mipmaps();
+        let uv_rect = texture.uv_rect();
+
+        // Keep the drawing order with colored triangles.
+        self.flush_colored_on_change();
 
-        {
-            // Set shader program and draw state.
-            let shader_program = self.textured.program;
-            self.use_program(shader_program);
+        // Flush when draw state, texture, color or alpha mode changes.
+        if self.current_draw_state.as_ref() != Some(draw_state) ||
+           self.textured.last_texture.as_ref().map(|t| t.id()) != Some(texture.get_id()) ||
+           self.textured.last_color != color ||
+           self.textured.last_premultiplied != premultiplied {
+            self.flush_textured_on_change();
+            self.use_premultiplied_blend(premultiplied);
             self.use_draw_state(draw_state);
+            self.textured.last_texture = Some(texture.handle());
+            self.textured.last_coverage = coverage;
+            self.textured.last_color = color;
+            self.textured.last_premultiplied = premultiplied;
         }
-        let ref mut shader = self.textured;
 
-        let texture = texture.get_id();
-        unsafe {
-            shader.pos.bind_vao(shader.vao);
-            shader.uv.bind_vao(shader.vao);
-            gl::BindTexture(gl::TEXTURE_2D, texture);
-            // Render triangles whether they are facing
-            // clockwise or counter clockwise.
-            gl::Disable(gl::CULL_FACE);
-            gl::BindVertexArray(shader.vao);
-            gl::Uniform4f(shader.color, color[0], color[1], color[2], color[3]);
-        }
+        // Overflowing the buffer flushes with the current program.
+        let program = self.textured.program.id();
+        self.use_program(program);
 
+        let ref mut shader = self.textured;
+        let ref mut stats = self.stats;
         f(&mut |vertices: &[[f32; 2]], texture_coords: &[[f32; 2]]| {
-            unsafe {
-                shader.pos.set(vertices);
-                shader.uv.set(texture_coords);
-                gl::DrawArrays(gl::TRIANGLES, 0, vertices.len() as i32);
+            let items = vertices.len();
+
+            // Render if there is not enough room.
+            if shader.offset + items > BUFFER_SIZE * CHUNKS {
+                stats.overflow_flushes += 1;
+                shader.flush(stats);
             }
-        });
 
-        unsafe {
-            gl::BindVertexArray(0);
-        }
+            shader.pos_buffer[shader.offset..shader.offset + items]
+                .copy_from_slice(vertices);
+            let uvs = &mut shader.uv_buffer[shader.offset..shader.offset + items];
+            match uv_rect {
+                // Maps the coordinates of a region into the shared texture.
+                Some(r) => {
+                    for (uv, tc) in uvs.iter_mut().zip(texture_coords) {
+                        *uv = [r[0] + tc[0] * r[2], r[1] + tc[1] * r[3]];
+                    }
+                }
+                None => uvs.copy_from_slice(&texture_coords[..items]),
+            }
+            shader.offset += items;
+        });
     }
 }
 
-// Might not fail if previous tests loaded functions.
+// Holds the lock of `MockGl`, so no other test has loaded functions meanwhile.
 #[test]
 #[should_panic]
 fn test_gl_loaded() {
+    let _lock = crate::testing::lock();
     GlGraphics::new(OpenGL::V3_2);
 }
+
+#[test]
+fn test_batches_triangles() {
+    use graphics::rectangle;
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    let c = Context::new_abs(100.0, 100.0);
+    rectangle([1.0, 0.0, 0.0, 1.0], [0.0, 0.0, 10.0, 10.0], c.transform, &mut g);
+    rectangle([0.0, 1.0, 0.0, 1.0], [20.0, 0.0, 10.0, 10.0], c.transform, &mut g);
+    g.flush_colored();
+
+    assert_eq!(mock.count("glDrawArrays"), 1);
+    assert_eq!(g.stats().draw_calls, 1);
+    assert_eq!(g.stats().vertices, 12);
+}
+
+#[test]
+fn test_color_space() {
+    use crate::testing::{Arg, MockGl};
+    use crate::{TextureFormat, TextureSettings};
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    let pixel = [255; 4];
+    let settings = TextureSettings::new();
+    let rgba = |pixel: &[u8]| {
+        Texture::from_memory(pixel, 1, 1, TextureFormat::Rgba8, &settings).unwrap()
+    };
+    let texture = Texture::from_memory_alpha(&pixel[..1], 1, 1, &settings).unwrap();
+    assert!(!texture.is_srgb());
+    assert!(!rgba(&pixel).is_srgb());
+
+    g.set_color_space(ColorSpace::Linear);
+    assert!(rgba(&pixel).is_srgb());
+
+    g.set_color_space(ColorSpace::Srgb);
+    g.clear_color([0.5, 0.5, 0.5, 1.0]);
+    assert_eq!(mock.calls_to("glClearColor")[0].args[0], Arg::Float(0.5));
+    assert!(!rgba(&pixel).is_srgb());
+}
+
+#[test]
+fn test_custom_blend() {
+    use graphics::rectangle;
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    let c = Context::new_abs(100.0, 100.0);
+    g.set_custom_blend(Some(CustomBlend::lighten())).unwrap();
+    rectangle([1.0, 0.0, 0.0, 1.0], [0.0, 0.0, 10.0, 10.0], c.transform, &mut g);
+    g.flush_colored();
+
+    let equations = mock.calls_to("glBlendEquationSeparate");
+    assert_eq!(equations.len(), 1);
+    assert_eq!(equations[0].int(0), gl::MAX as i64);
+    assert_eq!(equations[0].int(1), gl::MAX as i64);
+}
+
+#[test]
+fn test_clip_stack() {
+    use graphics::rectangle;
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    g.viewport(0, 0, 100, 100);
+    let c = Context::new_abs(100.0, 100.0);
+
+    g.push_clip_rect(c.transform, [10.0, 20.0, 30.0, 40.0]).unwrap();
+    rectangle([1.0; 4], [0.0, 0.0, 100.0, 100.0], c.transform, &mut g);
+    g.flush_colored();
+    let scissor = mock.calls_to("glScissor");
+    let scissor: Vec<i64> = (0..4).map(|i| scissor[0].int(i)).collect();
+    assert_eq!(scissor, [10, 40, 30, 40]);
+    assert_eq!(mock.count("glStencilOp"), 0);
+
+    g.push_clip(c.transform, &[[0.0, 0.0], [50.0, 0.0], [0.0, 50.0]]).unwrap();
+    assert_eq!(mock.calls_to("glStencilOp")[0].int(2), gl::INCR as i64);
+    assert_eq!(g.clip_depth(), 2);
+    g.pop_clip();
+    g.pop_clip();
+    assert_eq!(g.clip_depth(), 0);
+}
+
+#[test]
+fn test_without_vertex_array_objects() {
+    use graphics::rectangle;
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    mock.set_string(gl::VERSION, "OpenGL ES 2.0 Mock");
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    let c = Context::new_abs(100.0, 100.0);
+    rectangle([1.0; 4], [0.0, 0.0, 10.0, 10.0], c.transform, &mut g);
+    g.flush_colored();
+
+    assert!(!g.capabilities().vertex_array_objects);
+    assert_eq!(mock.count("glGenVertexArrays"), 0);
+    assert_eq!(mock.count("glBindVertexArray"), 0);
+    assert_eq!(mock.count("glVertexAttribPointer"), 2);
+    assert_eq!(mock.count("glDisableVertexAttribArray"), 2);
+}
+
+#[test]
+fn test_vertex_array_objects_not_loaded() {
+    use graphics::rectangle;
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    mock.set_string(gl::VERSION, "OpenGL ES 2.0 Mock");
+    mock.unload(&["glGenVertexArrays", "glBindVertexArray", "glDeleteVertexArrays"]);
+    let mut g = GlGraphics::try_new(OpenGL::V2_1).unwrap();
+    let c = Context::new_abs(100.0, 100.0);
+    rectangle([1.0; 4], [0.0, 0.0, 10.0, 10.0], c.transform, &mut g);
+    g.flush_colored();
+
+    assert!(!g.capabilities().vertex_array_objects);
+    assert_eq!(mock.count("glDrawArrays"), 1);
+}
+
+#[test]
+fn test_texture_outlives_batch() {
+    use graphics::Image;
+    use crate::testing::MockGl;
+    use crate::TextureSettings;
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    let c = Context::new_abs(100.0, 100.0);
+    let texture = Texture::from_memory_alpha(&[255], 1, 1, &TextureSettings::new()).unwrap();
+    let id = texture.get_id();
+    Image::new().rect([0.0, 0.0, 10.0, 10.0]).draw(&texture, &c.draw_state, c.transform, &mut g);
+    drop(texture);
+    assert_eq!(mock.count("glDeleteTextures"), 0);
+
+    g.flush_textured();
+    let draw = mock.calls().iter().position(|c| c.name == "glDrawArrays").unwrap();
+    let delete = mock.calls().iter().position(|c| c.name == "glDeleteTextures").unwrap();
+    assert!(draw < delete);
+    assert!(mock.calls_to("glBindTexture").iter().any(|c| c.int(1) == id as i64));
+}
+
+#[test]
+fn test_clear_color_flushes() {
+    use graphics::rectangle;
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    let c = Context::new_abs(100.0, 100.0);
+    rectangle([1.0; 4], [0.0, 0.0, 10.0, 10.0], c.transform, &mut g);
+    g.clear_color([0.0; 4]);
+
+    let names: Vec<_> = mock.calls()
+        .into_iter()
+        .map(|c| c.name)
+        .filter(|&name| name == "glDrawArrays" || name == "glClear")
+        .collect();
+    assert_eq!(names, ["glDrawArrays", "glClear"]);
+}
+
+#[test]
+fn test_texture_region() {
+    use graphics::{Image, ImageSize};
+    use crate::testing::MockGl;
+    use crate::TextureSettings;
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    let c = Context::new_abs(100.0, 100.0);
+    let texture = Texture::from_memory_alpha(&[0; 16], 4, 4, &TextureSettings::new()).unwrap();
+    let region = texture.region([2, 0, 2, 2]);
+    assert_eq!(region.get_size(), (2, 2));
+    Image::new().draw(&region, &c.draw_state, c.transform, &mut g);
+    g.flush_textured();
+
+    assert_eq!(mock.calls_to("glBindTexture").last().unwrap().int(1), texture.get_id() as i64);
+    // Positions are uploaded before texture coordinates.
+    let uploads = mock.calls_to("glBufferData");
+    let uvs = uploads.last().unwrap().data.clone().unwrap();
+    for uv in uvs.chunks(8) {
+        let u = f32::from_bits(u32::from_le_bytes([uv[0], uv[1], uv[2], uv[3]]));
+        let v = f32::from_bits(u32::from_le_bytes([uv[4], uv[5], uv[6], uv[7]]));
+        assert!(u == 0.5 || u == 1.0, "u = {}", u);
+        assert!(v == 0.0 || v == 0.5, "v = {}", v);
+    }
+}
+
+#[test]
+fn test_gpu_timing_extension() {
+    use crate::testing::{self, MockGl};
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    assert!(g.enable_gpu_timing(testing::get_proc_address).is_err());
+
+    mock.set_string(gl::EXTENSIONS, "GL_EXT_disjoint_timer_query");
+    capabilities::refresh();
+    // OpenGL ES 2.0 only has the functions with the suffix.
+    let load = |name: &str| if name.ends_with("EXT") {
+        testing::get_proc_address(&name[..name.len() - 3])
+    } else {
+        ::std::ptr::null()
+    };
+    g.enable_gpu_timing(load).unwrap();
+    let timer = g.gpu_timer.as_mut().unwrap();
+    assert_eq!(timer.begin(), None);
+    timer.end();
+    assert_eq!(mock.count("glGenQueries"), 1);
+    assert_eq!(mock.count("glBeginQuery"), 1);
+    assert_eq!(mock.count("glEndQuery"), 1);
+    assert!(mock.calls_to("glGetIntegerv").iter().any(|c| c.int(0) == 0x8FBB));
+}
+
+#[test]
+fn test_draw_to_without_clips() {
+    use graphics::rectangle;
+    use crate::testing::MockGl;
+    use crate::TextureSettings;
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    g.viewport(0, 0, 100, 100);
+    let c = Context::new_abs(100.0, 100.0);
+    g.push_clip_rect(c.transform, [10.0, 20.0, 30.0, 40.0]).unwrap();
+
+    let mut target = RenderTarget::new(4, 4, &TextureSettings::new()).unwrap();
+    let viewport = Viewport {
+        rect: [0, 0, 4, 4],
+        draw_size: [4, 4],
+        window_size: [4u32, 4].map(Into::into),
+    };
+    mock.clear();
+    g.draw_to(&mut target, viewport, |c, g| {
+        assert_eq!(g.clip_depth(), 0);
+        rectangle([1.0; 4], [0.0, 0.0, 4.0, 4.0], c.transform, g);
+    });
+    assert_eq!(mock.count("glScissor"), 0);
+    let scissor = gl::SCISSOR_TEST as i64;
+    assert!(mock.calls_to("glDisable").iter().any(|c| c.int(0) == scissor));
+
+    assert_eq!(g.clip_depth(), 1);
+    rectangle([1.0; 4], [0.0, 0.0, 100.0, 100.0], c.transform, &mut g);
+    g.flush_colored();
+    let scissor = mock.calls_to("glScissor");
+    let scissor: Vec<i64> = (0..4).map(|i| scissor[0].int(i)).collect();
+    assert_eq!(scissor, [10, 40, 30, 40]);
+}
+
+#[test]
+fn test_stencil_clip_limits() {
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    g.viewport(0, 0, 100, 100);
+    let c = Context::new_abs(100.0, 100.0);
+    let triangle = [[0.0, 0.0], [50.0, 0.0], [0.0, 50.0]];
+
+    mock.set_integer(gl::STENCIL_BITS, &[0]);
+    assert!(g.push_clip(c.transform, &triangle).is_err());
+    assert!(g.push_clip_rect(c.transform, [0.0, 0.0, 10.0, 10.0]).is_ok());
+    g.pop_clip();
+
+    mock.set_integer(gl::STENCIL_BITS, &[1]);
+    g.push_clip(c.transform, &triangle).unwrap();
+    assert!(g.push_clip(c.transform, &triangle).is_err());
+    assert_eq!(g.clip_depth(), 1);
+}
+
+#[test]
+fn test_flush_before_texture_update() {
+    use graphics::Image;
+    use crate::testing::MockGl;
+    use crate::TextureSettings;
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    let c = Context::new_abs(100.0, 100.0);
+    let mut texture = Texture::from_memory_alpha(&[0], 1, 1, &TextureSettings::new()).unwrap();
+    Image::new().rect([0.0, 0.0, 10.0, 10.0]).draw(&texture, &c.draw_state, c.transform, &mut g);
+    g.flush();
+    texture.update_region(&[255], [0, 0], [1, 1]).unwrap();
+
+    let names: Vec<_> = mock.calls()
+        .into_iter()
+        .map(|c| c.name)
+        .filter(|&name| name == "glDrawArrays" || name == "glTexSubImage2D")
+        .collect();
+    assert_eq!(names, ["glDrawArrays", "glTexSubImage2D"]);
+}
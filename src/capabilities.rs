@@ -0,0 +1,309 @@
+//! Capabilities of the current OpenGL context
+
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::rc::Rc;
+
+use crate::gl;
+use crate::gl::types::{GLenum, GLint};
+
+// Desktop OpenGL, missing from the OpenGL ES bindings.
+const CONTEXT_PROFILE_MASK: GLenum = 0x9126;
+const CONTEXT_CORE_PROFILE_BIT: u32 = 0x1;
+
+/// What the current OpenGL context supports, queried once when creating `GlGraphics`.
+///
+/// Applications can read it to pick asset quality tiers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The version string, such as `OpenGL ES 3.2 Mesa 23.0`.
+    pub version_string: String,
+    /// The shading language version string, such as `OpenGL ES GLSL ES 3.20`.
+    pub glsl_version_string: String,
+    /// The company responsible for the implementation.
+    pub vendor: String,
+    /// The name of the GPU or renderer.
+    pub renderer: String,
+    /// Whether the context is OpenGL ES rather than desktop OpenGL.
+    pub es: bool,
+    /// Whether the context is a desktop OpenGL core profile,
+    /// which lacks luminance and alpha texture formats.
+    pub core_profile: bool,
+    /// The major and minor version, `[0, 0]` if unknown.
+    pub version: [u32; 2],
+    /// The major and minor shading language version, such as `[3, 20]`, `[0, 0]` if unknown.
+    pub glsl_version: [u32; 2],
+    /// The supported extensions.
+    pub extensions: Vec<String>,
+    /// The largest width and height of a texture.
+    pub max_texture_size: u32,
+    /// The number of textures a fragment shader can sample.
+    pub max_texture_units: u32,
+    /// The stencil bits of the framebuffer bound when queried.
+    pub stencil_bits: u32,
+    /// The depth bits of the framebuffer bound when queried.
+    pub depth_bits: u32,
+    /// Whether vertex array objects are supported.
+    pub vertex_array_objects: bool,
+    /// Whether instanced drawing is supported.
+    pub instancing: bool,
+    /// Whether textures can remap their channels when sampled,
+    /// as OpenGL ES 3.0 and desktop OpenGL 3.3 do.
+    pub texture_swizzle: bool,
+    /// Whether textures can limit the mip levels sampled, as OpenGL ES 3.0 does,
+    /// so mip chains stopping early are complete.
+    pub texture_max_level: bool,
+    /// The supported compressed texture formats.
+    pub compressed_formats: Vec<GLenum>,
+}
+
+// Reads a string describing the current context.
+fn get_string(name: GLenum) -> String {
+    unsafe {
+        let ptr = gl::GetString(name);
+        if ptr.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(ptr as *const _).to_string_lossy().into_owned()
+        }
+    }
+}
+
+fn get_integer(name: GLenum) -> u32 {
+    let mut value: GLint = 0;
+    unsafe {
+        gl::GetIntegerv(name, &mut value);
+    }
+    value.max(0) as u32
+}
+
+// Parses the first `major.minor` number in a version string.
+fn parse_version(version: &str) -> [u32; 2] {
+    for word in version.split(' ') {
+        let mut parts = word.split('.');
+        let major = parts.next().and_then(|major| major.parse().ok());
+        let minor = parts.next()
+            .and_then(|minor| {
+                let digits: String = minor.chars().take_while(|c| c.is_digit(10)).collect();
+                digits.parse().ok()
+            });
+        if let (Some(major), Some(minor)) = (major, minor) {
+            return [major, minor];
+        }
+    }
+    [0, 0]
+}
+
+fn get_extensions(version: [u32; 2]) -> Vec<String> {
+    let extensions = get_string(gl::EXTENSIONS);
+    if !extensions.is_empty() || version[0] < 3 || !gl::GetStringi::is_loaded() {
+        return extensions.split(' ').filter(|ext| !ext.is_empty()).map(String::from).collect();
+    }
+    // Core profiles list the extensions one by one.
+    (0..get_integer(gl::NUM_EXTENSIONS))
+        .filter_map(|i| unsafe {
+            let ptr = gl::GetStringi(gl::EXTENSIONS, i);
+            if ptr.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(ptr as *const _).to_string_lossy().into_owned())
+            }
+        })
+        .collect()
+}
+
+// Gets the stencil and depth bits of the bound framebuffer from its attachments,
+// as core profiles require.
+fn get_framebuffer_bits() -> (u32, u32) {
+    let framebuffer = get_integer(gl::FRAMEBUFFER_BINDING);
+    let (stencil, depth) = if framebuffer == 0 {
+        (gl::STENCIL, gl::DEPTH)
+    } else {
+        (gl::STENCIL_ATTACHMENT, gl::DEPTH_ATTACHMENT)
+    };
+    let get = |attachment, pname| {
+        let mut value: GLint = 0;
+        unsafe {
+            gl::GetFramebufferAttachmentParameteriv(gl::FRAMEBUFFER, attachment, pname, &mut value);
+        }
+        value.max(0) as u32
+    };
+    (get(stencil, gl::FRAMEBUFFER_ATTACHMENT_STENCIL_SIZE),
+     get(depth, gl::FRAMEBUFFER_ATTACHMENT_DEPTH_SIZE))
+}
+
+impl Capabilities {
+    /// Queries the capabilities of the current context.
+    pub fn query() -> Self {
+        let version_string = get_string(gl::VERSION);
+        let glsl_version_string = get_string(gl::SHADING_LANGUAGE_VERSION);
+        let es = version_string.starts_with("OpenGL ES");
+        let version = parse_version(&version_string);
+        let extensions = get_extensions(version);
+        let has = |name: &str| extensions.iter().any(|ext| ext == name);
+
+        let core = if es { version[0] >= 3 } else { version >= [3, 0] };
+        // The bindings fall back to the `OES`, `EXT` and `ANGLE` entry points.
+        let vertex_array_objects = gl::GenVertexArrays::is_loaded() &&
+                                   gl::BindVertexArray::is_loaded() &&
+                                   (core || has("GL_ARB_vertex_array_object") ||
+                                    has("GL_OES_vertex_array_object"));
+        let instancing = gl::DrawArraysInstanced::is_loaded() &&
+                         (core && (es || version >= [3, 1]) ||
+                          has("GL_ARB_draw_instanced") ||
+                          has("GL_EXT_draw_instanced") ||
+                          has("GL_EXT_instanced_arrays") ||
+                          has("GL_ANGLE_instanced_arrays"));
+        let texture_swizzle = if es { version[0] >= 3 } else { version >= [3, 3] } ||
+                              has("GL_ARB_texture_swizzle") ||
+                              has("GL_EXT_texture_swizzle");
+        let texture_max_level = !es || version[0] >= 3 || has("GL_APPLE_texture_max_level");
+
+        let count = get_integer(gl::NUM_COMPRESSED_TEXTURE_FORMATS) as usize;
+        let mut formats = vec![0; count];
+        if count > 0 {
+            unsafe {
+                gl::GetIntegerv(gl::COMPRESSED_TEXTURE_FORMATS, formats.as_mut_ptr());
+            }
+        }
+
+        let core_profile = !es && version >= [3, 2] &&
+                           get_integer(CONTEXT_PROFILE_MASK) & CONTEXT_CORE_PROFILE_BIT != 0;
+        let (stencil_bits, depth_bits) = if core_profile {
+            get_framebuffer_bits()
+        } else {
+            (get_integer(gl::STENCIL_BITS), get_integer(gl::DEPTH_BITS))
+        };
+        // Queries unknown to the context fail harmlessly, such as the extension string
+        // of core profiles, whose errors would be reported by the next checked call.
+        for _ in 0..8 {
+            if unsafe { gl::GetError() } == gl::NO_ERROR {
+                break;
+            }
+        }
+
+        Capabilities {
+            vendor: get_string(gl::VENDOR),
+            renderer: get_string(gl::RENDERER),
+            es: es,
+            core_profile: core_profile,
+            version: version,
+            glsl_version: parse_version(&glsl_version_string),
+            max_texture_size: get_integer(gl::MAX_TEXTURE_SIZE),
+            max_texture_units: get_integer(gl::MAX_TEXTURE_IMAGE_UNITS),
+            stencil_bits: stencil_bits,
+            depth_bits: depth_bits,
+            vertex_array_objects: vertex_array_objects,
+            instancing: instancing,
+            texture_swizzle: texture_swizzle,
+            texture_max_level: texture_max_level,
+            compressed_formats: formats.into_iter().map(|f| f as GLenum).collect(),
+            version_string: version_string,
+            glsl_version_string: glsl_version_string,
+            extensions: extensions,
+        }
+    }
+
+    /// Returns `true` if the context supports the named extension, such as `GL_OES_texture_npot`.
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|ext| ext == name)
+    }
+
+    /// Returns `true` if the context has the features of OpenGL ES 3.0,
+    /// as OpenGL ES 3.0 and later and desktop OpenGL 3.0 and later do.
+    pub fn has_es3_features(&self) -> bool {
+        if self.es {
+            self.version[0] >= 3
+        } else {
+            self.version >= [3, 0]
+        }
+    }
+
+    /// Returns `true` if textures with sizes other than powers of two can repeat
+    /// and have mipmaps, which OpenGL ES 2.0 only does with `OES_texture_npot`.
+    pub fn npot_textures(&self) -> bool {
+        !(self.es && self.version[0] < 3) || self.has_extension("GL_OES_texture_npot")
+    }
+
+    /// Returns `true` if textures can use the compressed format.
+    pub fn supports_compressed_format(&self, format: GLenum) -> bool {
+        self.compressed_formats.contains(&format)
+    }
+
+    /// Gets the stencil bits of the framebuffer bound now, such as a render target,
+    /// unlike `stencil_bits`.
+    pub(crate) fn bound_stencil_bits(&self) -> u32 {
+        if !self.core_profile {
+            return get_integer(gl::STENCIL_BITS);
+        }
+        if get_integer(gl::FRAMEBUFFER_BINDING) != 0 {
+            // Querying the size of a missing attachment is an error.
+            let mut kind: GLint = 0;
+            unsafe {
+                gl::GetFramebufferAttachmentParameteriv(gl::FRAMEBUFFER,
+                                                        gl::STENCIL_ATTACHMENT,
+                                                        gl::FRAMEBUFFER_ATTACHMENT_OBJECT_TYPE,
+                                                        &mut kind);
+            }
+            if kind as GLenum == gl::NONE {
+                return 0;
+            }
+        }
+        get_framebuffer_bits().0
+    }
+}
+
+thread_local! {
+    // The capabilities of the context current on this thread.
+    static CURRENT: RefCell<Option<Rc<Capabilities>>> = RefCell::new(None);
+}
+
+/// Gets the capabilities of the current context, querying them the first time.
+pub(crate) fn current() -> Rc<Capabilities> {
+    CURRENT.with(|current| {
+        current.borrow_mut().get_or_insert_with(|| Rc::new(Capabilities::query())).clone()
+    })
+}
+
+/// Queries the capabilities again, such as in a new context.
+pub(crate) fn refresh() -> Rc<Capabilities> {
+    let capabilities = Rc::new(Capabilities::query());
+    CURRENT.with(|current| *current.borrow_mut() = Some(capabilities.clone()));
+    capabilities
+}
+
+/// Forgets the capabilities, which are queried again when needed.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) fn reset() {
+    CURRENT.with(|current| *current.borrow_mut() = None);
+}
+
+#[test]
+fn test_parse_version() {
+    assert_eq!(parse_version("OpenGL ES 3.2 Mesa 23.0.4"), [3, 2]);
+    assert_eq!(parse_version("OpenGL ES GLSL ES 3.20"), [3, 20]);
+    assert_eq!(parse_version("4.6.0 NVIDIA 535.54"), [4, 6]);
+    assert_eq!(parse_version("1.30 NVIDIA via Cg compiler"), [1, 30]);
+    assert_eq!(parse_version(""), [0, 0]);
+}
+
+#[test]
+fn test_es2_extensions() {
+    use crate::testing::MockGl;
+
+    let query = |extensions: &str| {
+        let mock = MockGl::new();
+        mock.set_string(gl::VERSION, "OpenGL ES 2.0 Mock");
+        mock.set_string(gl::EXTENSIONS, extensions);
+        Capabilities::query()
+    };
+    let none = query("");
+    assert!(!none.vertex_array_objects);
+    assert!(!none.instancing);
+    assert!(query("GL_OES_vertex_array_object").vertex_array_objects);
+    for ext in &["GL_EXT_draw_instanced", "GL_EXT_instanced_arrays", "GL_ANGLE_instanced_arrays"] {
+        let capabilities = query(ext);
+        assert!(capabilities.instancing, "{}", ext);
+        assert!(!capabilities.vertex_array_objects, "{}", ext);
+    }
+}
@@ -0,0 +1,19 @@
+//! OpenGL bindings that check for errors after each call, used with the `gl-debug` feature.
+//!
+//! Generated by `build.rs` from the same registry as `gl.rs`, wrapping every function
+//! except `GetError`.
+
+#![allow(non_snake_case, clippy::too_many_arguments)]
+
+use std::panic::Location;
+
+use crate::debug::check_errors;
+
+// Also provides `types`, which a private import would hide from users of this module.
+pub use crate::gl::*;
+
+mod __gl_imports {
+    pub use std::os::raw;
+}
+
+include!(concat!(env!("OUT_DIR"), "/checked_gl.rs"));
@@ -0,0 +1,56 @@
+//! Color spaces of drawing
+
+use std::cell::Cell;
+
+use graphics::color::gamma_srgb_to_linear;
+use graphics::types::Color;
+
+/// The color space colors are blended in.
+///
+/// Colors passed to `GlGraphics` and the pixels of textures are sRGB in both cases.
+/// The default is `Srgb`, which stores and draws everything as before color spaces existed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// Blends in linear space, which is physically correct.
+    ///
+    /// Colors are converted to linear, and color textures are stored as sRGB
+    /// on OpenGL ES 3.0 so sampling converts them too.
+    /// The framebuffer must be sRGB to convert the result back,
+    /// as render targets are. For windows, this is chosen when creating the surface,
+    /// so this is opt-in: on an ordinary surface everything renders darker.
+    Linear,
+    /// Treats everything as sRGB without conversion,
+    /// matching how most image editors blend colors.
+    Srgb,
+}
+
+impl Default for ColorSpace {
+    fn default() -> ColorSpace {
+        ColorSpace::Srgb
+    }
+}
+
+impl ColorSpace {
+    /// Converts an sRGB color to this color space.
+    pub fn convert(&self, color: Color) -> Color {
+        match *self {
+            ColorSpace::Linear => gamma_srgb_to_linear(color),
+            ColorSpace::Srgb => color,
+        }
+    }
+}
+
+thread_local! {
+    // The color space of the back-end on this thread, used by textures created here.
+    static COLOR_SPACE: Cell<ColorSpace> = Cell::new(ColorSpace::Srgb);
+}
+
+/// Gets the color space textures are created for.
+pub(crate) fn current() -> ColorSpace {
+    COLOR_SPACE.with(|c| c.get())
+}
+
+/// Sets the color space textures are created for.
+pub(crate) fn set_current(color_space: ColorSpace) {
+    COLOR_SPACE.with(|c| c.set(color_space));
+}
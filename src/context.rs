@@ -0,0 +1,36 @@
+//! Tracking of OpenGL context generations.
+//!
+//! When the context is lost, for example when an Android app is paused,
+//! every object id becomes invalid. Objects remember the generation they
+//! were created in and skip deleting ids from an older context,
+//! since those ids may have been reused by the new one.
+
+use std::cell::Cell;
+
+thread_local! {
+    static GENERATION: Cell<u32> = Cell::new(0);
+}
+
+/// Gets the generation of the current context.
+pub fn generation() -> u32 {
+    GENERATION.with(|g| g.get())
+}
+
+/// Returns `true` if an object created in `generation` belongs to the current context.
+pub fn is_current(generation: u32) -> bool {
+    self::generation() == generation
+}
+
+/// Marks the objects of the current context as lost.
+pub fn context_lost() {
+    GENERATION.with(|g| g.set(g.get().wrapping_add(1)));
+}
+
+#[test]
+fn test_context_lost() {
+    let before = generation();
+    assert!(is_current(before));
+    context_lost();
+    assert!(!is_current(before));
+    assert!(is_current(generation()));
+}
@@ -0,0 +1,314 @@
+//! OpenGL error checking and debug messages, enabled by the `gl-debug` feature.
+//!
+//! With the feature, `glGetError` is checked after every OpenGL call made by this crate,
+//! so errors are reported here as they happen, and still returned as `Error::GlError`.
+//! Messages go to the handler set with `set_message_handler`,
+//! or to the `log` crate when there is none.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::ffi::CStr;
+use std::os::raw::c_void;
+use std::panic::{self, AssertUnwindSafe, Location};
+
+use log;
+
+use crate::gl;
+use crate::gl::types::{GLchar, GLenum, GLsizei, GLuint};
+use crate::error::Error;
+
+// `KHR_debug`, which is not part of the generated bindings.
+const DEBUG_OUTPUT: GLenum = 0x92E0;
+const DEBUG_OUTPUT_SYNCHRONOUS: GLenum = 0x8242;
+const DEBUG_SEVERITY_HIGH: GLenum = 0x9146;
+const DEBUG_SEVERITY_MEDIUM: GLenum = 0x9147;
+const DEBUG_SEVERITY_LOW: GLenum = 0x9148;
+const DEBUG_SOURCE_API: GLenum = 0x8246;
+const DEBUG_TYPE_ERROR: GLenum = 0x824C;
+
+// The errors read after a call, as in `Capabilities::query`.
+const MAX_ERRORS: usize = 8;
+
+type DebugProc = extern "system" fn(GLenum,
+                                    GLenum,
+                                    GLuint,
+                                    GLenum,
+                                    GLsizei,
+                                    *const GLchar,
+                                    *mut c_void);
+type DebugMessageCallback = extern "system" fn(DebugProc, *const c_void);
+
+/// The severity of a debug message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// Errors and undefined behavior.
+    High,
+    /// Performance problems and deprecated usage.
+    Medium,
+    /// Redundant state changes and minor performance problems.
+    Low,
+    /// Anything else.
+    Notification,
+}
+
+/// A message from `glGetError` or the `KHR_debug` callback.
+#[derive(Clone, Copy, Debug)]
+pub struct DebugMessage<'a> {
+    /// The severity of the message.
+    pub severity: Severity,
+    /// The source, such as `GL_DEBUG_SOURCE_API`.
+    pub source: GLenum,
+    /// The type, such as `GL_DEBUG_TYPE_ERROR`.
+    pub kind: GLenum,
+    /// The error code for errors, otherwise an id chosen by the driver.
+    pub id: GLuint,
+    /// The text of the message.
+    pub text: &'a str,
+}
+
+thread_local! {
+    static HANDLER: RefCell<Option<Box<dyn FnMut(&DebugMessage)>>> = RefCell::new(None);
+    // The first error consumed by `check_errors`, until taken by `check_gl_error`.
+    static PENDING_ERROR: Cell<GLenum> = Cell::new(gl::NO_ERROR);
+    // A panic of the handler inside `debug_callback`, resumed once back from the driver.
+    static PENDING_PANIC: RefCell<Option<Box<dyn Any + Send>>> = RefCell::new(None);
+}
+
+/// Sets the handler receiving the debug messages of the current thread.
+pub fn set_message_handler<F>(handler: F)
+    where F: FnMut(&DebugMessage) + 'static
+{
+    HANDLER.with(|h| *h.borrow_mut() = Some(Box::new(handler)));
+}
+
+/// Removes the handler of the current thread, sending messages to the `log` crate again.
+pub fn clear_message_handler() {
+    HANDLER.with(|h| *h.borrow_mut() = None);
+}
+
+/// Gets the name of an OpenGL error code or debug enum.
+pub fn enum_name(value: GLenum) -> Option<&'static str> {
+    Some(match value {
+        gl::NO_ERROR => "GL_NO_ERROR",
+        gl::INVALID_ENUM => "GL_INVALID_ENUM",
+        gl::INVALID_VALUE => "GL_INVALID_VALUE",
+        gl::INVALID_OPERATION => "GL_INVALID_OPERATION",
+        gl::INVALID_FRAMEBUFFER_OPERATION => "GL_INVALID_FRAMEBUFFER_OPERATION",
+        gl::OUT_OF_MEMORY => "GL_OUT_OF_MEMORY",
+        0x0503 => "GL_STACK_OVERFLOW",
+        0x0504 => "GL_STACK_UNDERFLOW",
+        DEBUG_SOURCE_API => "GL_DEBUG_SOURCE_API",
+        0x8247 => "GL_DEBUG_SOURCE_WINDOW_SYSTEM",
+        0x8248 => "GL_DEBUG_SOURCE_SHADER_COMPILER",
+        0x8249 => "GL_DEBUG_SOURCE_THIRD_PARTY",
+        0x824A => "GL_DEBUG_SOURCE_APPLICATION",
+        0x824B => "GL_DEBUG_SOURCE_OTHER",
+        DEBUG_TYPE_ERROR => "GL_DEBUG_TYPE_ERROR",
+        0x824D => "GL_DEBUG_TYPE_DEPRECATED_BEHAVIOR",
+        0x824E => "GL_DEBUG_TYPE_UNDEFINED_BEHAVIOR",
+        0x824F => "GL_DEBUG_TYPE_PORTABILITY",
+        0x8250 => "GL_DEBUG_TYPE_PERFORMANCE",
+        0x8251 => "GL_DEBUG_TYPE_OTHER",
+        0x8268 => "GL_DEBUG_TYPE_MARKER",
+        _ => return None,
+    })
+}
+
+fn dispatch(message: &DebugMessage) {
+    // A handler making OpenGL calls that fail reports to the log instead.
+    let handled = HANDLER.with(|h| match h.try_borrow_mut() {
+        Ok(mut handler) => {
+            match *handler {
+                Some(ref mut handler) => {
+                    handler(message);
+                    true
+                }
+                None => false,
+            }
+        }
+        Err(_) => false,
+    });
+    if !handled {
+        let level = match message.severity {
+            Severity::High => log::Level::Error,
+            Severity::Medium => log::Level::Warn,
+            Severity::Low => log::Level::Info,
+            Severity::Notification => log::Level::Debug,
+        };
+        log::log!(level, "{}", message.text);
+    }
+}
+
+/// Takes the first error consumed by `check_errors`, like `glGetError` without the feature.
+pub(crate) fn take_error() -> GLenum {
+    PENDING_ERROR.with(|e| e.replace(gl::NO_ERROR))
+}
+
+/// Reports the errors raised by the OpenGL function `call`, called at `location`.
+pub(crate) fn check_errors(call: &str, location: &Location) {
+    if let Some(payload) = PENDING_PANIC.with(|p| p.borrow_mut().take()) {
+        panic::resume_unwind(payload);
+    }
+    // Lost contexts may never stop reporting errors, so only a few are read.
+    for _ in 0..MAX_ERRORS {
+        let code = unsafe { gl::GetError() };
+        if code == gl::NO_ERROR {
+            return;
+        }
+        PENDING_ERROR.with(|e| if e.get() == gl::NO_ERROR {
+            e.set(code);
+        });
+        let text = format!("{} failed with {} at {}:{}",
+                           call,
+                           enum_name(code).unwrap_or("an unknown error"),
+                           location.file(),
+                           location.line());
+        report_error(code, &text);
+    }
+    let code = unsafe { gl::GetError() };
+    if code != gl::NO_ERROR {
+        let text = format!("{} raised more than {} errors at {}:{}, ignoring the rest",
+                           call,
+                           MAX_ERRORS,
+                           location.file(),
+                           location.line());
+        report_error(code, &text);
+    }
+}
+
+fn report_error(code: GLenum, text: &str) {
+    dispatch(&DebugMessage {
+        severity: Severity::High,
+        source: DEBUG_SOURCE_API,
+        kind: DEBUG_TYPE_ERROR,
+        id: code,
+        text: text,
+    });
+}
+
+extern "system" fn debug_callback(source: GLenum,
+                                  kind: GLenum,
+                                  id: GLuint,
+                                  severity: GLenum,
+                                  _length: GLsizei,
+                                  message: *const GLchar,
+                                  _user_param: *mut c_void) {
+    let text = if message.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(message).to_string_lossy().into_owned() }
+    };
+    let severity = match severity {
+        DEBUG_SEVERITY_HIGH => Severity::High,
+        DEBUG_SEVERITY_MEDIUM => Severity::Medium,
+        DEBUG_SEVERITY_LOW => Severity::Low,
+        _ => Severity::Notification,
+    };
+    // Unwinding into the driver is undefined behavior, so a panic is kept for `check_errors`.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        dispatch(&DebugMessage {
+            severity: severity,
+            source: source,
+            kind: kind,
+            id: id,
+            text: &text,
+        })
+    }));
+    if let Err(payload) = result {
+        PENDING_PANIC.with(|p| *p.borrow_mut() = Some(payload));
+    }
+}
+
+/// Installs a `KHR_debug` message callback, routing driver messages to the message handler.
+///
+/// `load` resolves OpenGL function names, like the function passed to `gl::load_with`.
+/// Messages are delivered synchronously, on the thread making the OpenGL calls.
+/// Returns `Err` if the context does not support `KHR_debug`.
+pub fn install_message_callback<F>(mut load: F) -> Result<(), Error>
+    where F: FnMut(&str) -> *const c_void
+{
+    let mut ptr = load("glDebugMessageCallback");
+    if ptr.is_null() {
+        ptr = load("glDebugMessageCallbackKHR");
+    }
+    if ptr.is_null() {
+        return Err(Error::Unsupported("KHR_debug is not supported by the OpenGL context"
+            .to_string()));
+    }
+    unsafe {
+        let callback: DebugMessageCallback = ::std::mem::transmute(ptr);
+        gl::Enable(DEBUG_OUTPUT);
+        gl::Enable(DEBUG_OUTPUT_SYNCHRONOUS);
+        callback(debug_callback, ::std::ptr::null());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_checked_error_is_returned() {
+    use std::rc::Rc;
+    use crate::checked_gl;
+    use crate::error::check_gl_error;
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    let ids = Rc::new(RefCell::new(Vec::new()));
+    let handled = ids.clone();
+    set_message_handler(move |message| handled.borrow_mut().push(message.id));
+    mock.set_error(gl::INVALID_VALUE);
+    unsafe { checked_gl::Flush() };
+    clear_message_handler();
+    assert_eq!(*ids.borrow(), vec![gl::INVALID_VALUE]);
+    match check_gl_error("glFlush") {
+        Err(Error::GlError(code, _)) => assert_eq!(code, gl::INVALID_VALUE),
+        _ => panic!("The error was consumed by the checked call"),
+    }
+    assert!(check_gl_error("glFlush").is_ok());
+}
+
+#[test]
+fn test_persistent_errors_are_bounded() {
+    use std::rc::Rc;
+    use crate::checked_gl;
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    let texts = Rc::new(RefCell::new(Vec::new()));
+    let handled = texts.clone();
+    set_message_handler(move |message| handled.borrow_mut().push(message.text.to_string()));
+    mock.set_persistent_error(gl::OUT_OF_MEMORY);
+    unsafe { checked_gl::Flush() };
+    clear_message_handler();
+    mock.set_error(gl::NO_ERROR);
+
+    let texts = texts.borrow();
+    assert_eq!(texts.len(), MAX_ERRORS + 1);
+    assert!(texts[MAX_ERRORS].contains("ignoring the rest"));
+}
+
+#[test]
+fn test_callback_panic_is_resumed() {
+    use crate::checked_gl;
+    use crate::testing::MockGl;
+
+    let _mock = MockGl::new();
+    set_message_handler(|_| panic!("Handler panicked"));
+    debug_callback(DEBUG_SOURCE_API,
+                   DEBUG_TYPE_ERROR,
+                   0,
+                   DEBUG_SEVERITY_HIGH,
+                   0,
+                   ::std::ptr::null(),
+                   ::std::ptr::null_mut());
+    clear_message_handler();
+    let result = panic::catch_unwind(|| unsafe { checked_gl::Flush() });
+    assert!(result.is_err());
+    assert!(panic::catch_unwind(|| unsafe { checked_gl::Flush() }).is_ok());
+}
+
+#[test]
+fn test_enum_name() {
+    assert_eq!(enum_name(gl::INVALID_ENUM), Some("GL_INVALID_ENUM"));
+    assert_eq!(enum_name(DEBUG_TYPE_ERROR), Some("GL_DEBUG_TYPE_ERROR"));
+    assert_eq!(enum_name(0x1234), None);
+}
@@ -1,15 +1,19 @@
-use crate::gl;
+use crate::checked_gl as gl;
 use graphics::draw_state::*;
 
-pub fn bind_state(old_state: &DrawState, new_state: &DrawState) {
+// A custom blend replaces the blend of the draw states.
+pub fn bind_state(old_state: &DrawState,
+                  new_state: &DrawState,
+                  custom_blend: bool,
+                  premultiplied: bool) {
     if old_state.scissor != new_state.scissor {
         bind_scissor(new_state.scissor);
     }
     if old_state.stencil != new_state.stencil {
         bind_stencil(new_state.stencil);
     }
-    if old_state.blend != new_state.blend {
-        bind_blend(new_state.blend);
+    if old_state.blend != new_state.blend && !custom_blend {
+        bind_blend(new_state.blend, premultiplied);
     }
 }
 
@@ -54,45 +58,223 @@ pub fn bind_stencil(stencil: Option<Stencil>) {
     }
 }
 
-/*
-fn map_equation(eq: Equation) -> gl::types::GLenum {
+// How the clip stack of `GlGraphics` uses the stencil buffer,
+// where the stencil value of a pixel is the number of clips containing it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClipStencil {
+    // Draws inside the clips of the given depth.
+    Inside(u8),
+    // Adds a clip inside those of the given depth, without drawing colors.
+    Push(u8),
+    // Lowers the depth of all pixels to the given depth, without drawing colors.
+    Pop(u8),
+}
+
+pub fn bind_clip_stencil(clip: ClipStencil) {
+    unsafe {
+        gl::Enable(gl::STENCIL_TEST);
+        gl::StencilMask(255);
+        match clip {
+            ClipStencil::Inside(depth) => {
+                gl::StencilFunc(gl::EQUAL, depth as gl::types::GLint, 255);
+                gl::StencilOp(gl::KEEP, gl::KEEP, gl::KEEP);
+            }
+            ClipStencil::Push(depth) => {
+                gl::StencilFunc(gl::EQUAL, depth as gl::types::GLint, 255);
+                gl::StencilOp(gl::KEEP, gl::KEEP, gl::INCR);
+            }
+            ClipStencil::Pop(depth) => {
+                // Passes where the depth is less than the stencil value.
+                gl::StencilFunc(gl::LESS, depth as gl::types::GLint, 255);
+                gl::StencilOp(gl::KEEP, gl::KEEP, gl::REPLACE);
+            }
+        }
+        let color = match clip {
+            ClipStencil::Inside(_) => gl::TRUE,
+            ClipStencil::Push(_) | ClipStencil::Pop(_) => gl::FALSE,
+        };
+        gl::ColorMask(color, color, color, color);
+    }
+}
+
+/// How the source and destination are combined when blending.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlendEquation {
+    /// `source * source factor + destination * destination factor`.
+    Add,
+    /// `source * source factor - destination * destination factor`.
+    Subtract,
+    /// `destination * destination factor - source * source factor`.
+    ReverseSubtract,
+    /// The smaller of source and destination, ignoring the factors.
+    /// Requires OpenGL ES 3.0 or `EXT_blend_minmax`.
+    Min,
+    /// The larger of source and destination, ignoring the factors.
+    /// Requires OpenGL ES 3.0 or `EXT_blend_minmax`.
+    Max,
+}
+
+/// What the source or destination is multiplied with when blending.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlendFactor {
+    /// 0.
+    Zero,
+    /// 1.
+    One,
+    /// The source color.
+    SourceColor,
+    /// 1 - the source color.
+    OneMinusSourceColor,
+    /// The destination color.
+    DestinationColor,
+    /// 1 - the destination color.
+    OneMinusDestinationColor,
+    /// The source alpha.
+    SourceAlpha,
+    /// 1 - the source alpha.
+    OneMinusSourceAlpha,
+    /// The destination alpha.
+    DestinationAlpha,
+    /// 1 - the destination alpha.
+    OneMinusDestinationAlpha,
+    /// The constant color.
+    ConstantColor,
+    /// 1 - the constant color.
+    OneMinusConstantColor,
+    /// The constant alpha.
+    ConstantAlpha,
+    /// 1 - the constant alpha.
+    OneMinusConstantAlpha,
+    /// The smaller of the source alpha and 1 - the destination alpha, 1 for alpha.
+    SourceAlphaSaturate,
+}
+
+/// A blend with separate equations and factors for color and alpha,
+/// for effects the presets of `graphics::draw_state::Blend` can not express.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CustomBlend {
+    /// The equation of the color channels.
+    pub color_equation: BlendEquation,
+    /// The factor of the source color.
+    pub color_source: BlendFactor,
+    /// The factor of the destination color.
+    pub color_destination: BlendFactor,
+    /// The equation of the alpha channel.
+    pub alpha_equation: BlendEquation,
+    /// The factor of the source alpha.
+    pub alpha_source: BlendFactor,
+    /// The factor of the destination alpha.
+    pub alpha_destination: BlendFactor,
+    /// The constant color used by the constant factors.
+    pub constant: [f32; 4],
+}
+
+impl CustomBlend {
+    /// Creates a blend using the same equation and factors for color and alpha.
+    pub fn new(equation: BlendEquation, source: BlendFactor, destination: BlendFactor) -> Self {
+        CustomBlend {
+            color_equation: equation,
+            color_source: source,
+            color_destination: destination,
+            alpha_equation: equation,
+            alpha_source: source,
+            alpha_destination: destination,
+            constant: [1.0; 4],
+        }
+    }
+
+    /// Blends sources whose colors are multiplied with their alpha.
+    pub fn premultiplied_alpha() -> Self {
+        CustomBlend::new(BlendEquation::Add,
+                         BlendFactor::One,
+                         BlendFactor::OneMinusSourceAlpha)
+    }
+
+    /// Brightens the destination by the inverse of the source, `1 - (1 - s) * (1 - d)`.
+    pub fn screen() -> Self {
+        CustomBlend {
+            color_destination: BlendFactor::OneMinusSourceColor,
+            ..CustomBlend::premultiplied_alpha()
+        }
+    }
+
+    /// Keeps the larger of source and destination.
+    pub fn lighten() -> Self {
+        CustomBlend::new(BlendEquation::Max, BlendFactor::One, BlendFactor::One)
+    }
+
+    /// Keeps the smaller of source and destination.
+    pub fn darken() -> Self {
+        CustomBlend::new(BlendEquation::Min, BlendFactor::One, BlendFactor::One)
+    }
+
+    /// Returns `true` if the blend uses the `Min` or `Max` equations.
+    pub fn uses_min_max(&self) -> bool {
+        [self.color_equation, self.alpha_equation]
+            .iter()
+            .any(|eq| *eq == BlendEquation::Min || *eq == BlendEquation::Max)
+    }
+}
+
+fn map_equation(eq: BlendEquation) -> gl::types::GLenum {
     match eq {
-        Equation::Add    => gl::FUNC_ADD,
-        Equation::Sub    => gl::FUNC_SUBTRACT,
-        Equation::RevSub => gl::FUNC_REVERSE_SUBTRACT,
-        Equation::Min    => gl::MIN,
-        Equation::Max    => gl::MAX,
+        BlendEquation::Add => gl::FUNC_ADD,
+        BlendEquation::Subtract => gl::FUNC_SUBTRACT,
+        BlendEquation::ReverseSubtract => gl::FUNC_REVERSE_SUBTRACT,
+        BlendEquation::Min => gl::MIN,
+        BlendEquation::Max => gl::MAX,
     }
 }
 
-fn map_factor(factor: Factor) -> gl::types::GLenum {
+fn map_factor(factor: BlendFactor) -> gl::types::GLenum {
     match factor {
-        Factor::Zero                              => gl::ZERO,
-        Factor::One                               => gl::ONE,
-        Factor::ZeroPlus(BlendValue::SourceColor) => gl::SRC_COLOR,
-        Factor::OneMinus(BlendValue::SourceColor) => gl::ONE_MINUS_SRC_COLOR,
-        Factor::ZeroPlus(BlendValue::SourceAlpha) => gl::SRC_ALPHA,
-        Factor::OneMinus(BlendValue::SourceAlpha) => gl::ONE_MINUS_SRC_ALPHA,
-        Factor::ZeroPlus(BlendValue::DestColor)   => gl::DST_COLOR,
-        Factor::OneMinus(BlendValue::DestColor)   => gl::ONE_MINUS_DST_COLOR,
-        Factor::ZeroPlus(BlendValue::DestAlpha)   => gl::DST_ALPHA,
-        Factor::OneMinus(BlendValue::DestAlpha)   => gl::ONE_MINUS_DST_ALPHA,
-        Factor::ZeroPlus(BlendValue::ConstColor)  => gl::CONSTANT_COLOR,
-        Factor::OneMinus(BlendValue::ConstColor)  => gl::ONE_MINUS_CONSTANT_COLOR,
-        Factor::ZeroPlus(BlendValue::ConstAlpha)  => gl::CONSTANT_ALPHA,
-        Factor::OneMinus(BlendValue::ConstAlpha)  => gl::ONE_MINUS_CONSTANT_ALPHA,
-        Factor::SourceAlphaSaturated => gl::SRC_ALPHA_SATURATE,
+        BlendFactor::Zero => gl::ZERO,
+        BlendFactor::One => gl::ONE,
+        BlendFactor::SourceColor => gl::SRC_COLOR,
+        BlendFactor::OneMinusSourceColor => gl::ONE_MINUS_SRC_COLOR,
+        BlendFactor::DestinationColor => gl::DST_COLOR,
+        BlendFactor::OneMinusDestinationColor => gl::ONE_MINUS_DST_COLOR,
+        BlendFactor::SourceAlpha => gl::SRC_ALPHA,
+        BlendFactor::OneMinusSourceAlpha => gl::ONE_MINUS_SRC_ALPHA,
+        BlendFactor::DestinationAlpha => gl::DST_ALPHA,
+        BlendFactor::OneMinusDestinationAlpha => gl::ONE_MINUS_DST_ALPHA,
+        BlendFactor::ConstantColor => gl::CONSTANT_COLOR,
+        BlendFactor::OneMinusConstantColor => gl::ONE_MINUS_CONSTANT_COLOR,
+        BlendFactor::ConstantAlpha => gl::CONSTANT_ALPHA,
+        BlendFactor::OneMinusConstantAlpha => gl::ONE_MINUS_CONSTANT_ALPHA,
+        BlendFactor::SourceAlphaSaturate => gl::SRC_ALPHA_SATURATE,
+    }
+}
+
+pub fn bind_custom_blend(blend: &CustomBlend) {
+    unsafe {
+        gl::Enable(gl::BLEND);
+        let c = blend.constant;
+        gl::BlendColor(c[0], c[1], c[2], c[3]);
+        gl::BlendEquationSeparate(map_equation(blend.color_equation),
+                                  map_equation(blend.alpha_equation));
+        gl::BlendFuncSeparate(map_factor(blend.color_source),
+                              map_factor(blend.color_destination),
+                              map_factor(blend.alpha_source),
+                              map_factor(blend.alpha_destination));
     }
 }
-*/
 
-pub fn bind_blend(blend: Option<Blend>) {
+// Sources with premultiplied alpha are blended as such with `Blend::Alpha`.
+pub fn bind_blend(blend: Option<Blend>, premultiplied: bool) {
     unsafe {
         match blend {
             Some(b) => {
                 gl::Enable(gl::BLEND);
                 gl::BlendColor(1.0, 1.0, 1.0, 1.0);
                 match b {
+                    Blend::Alpha if premultiplied => {
+                        gl::BlendEquationSeparate(gl::FUNC_ADD, gl::FUNC_ADD);
+                        gl::BlendFuncSeparate(gl::ONE,
+                                              gl::ONE_MINUS_SRC_ALPHA,
+                                              gl::ONE,
+                                              gl::ONE_MINUS_SRC_ALPHA);
+                    }
                     Blend::Alpha => {
                         gl::BlendEquationSeparate(gl::FUNC_ADD, gl::FUNC_ADD);
                         gl::BlendFuncSeparate(gl::SRC_ALPHA,
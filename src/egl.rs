@@ -0,0 +1,251 @@
+//! Headless OpenGL ES contexts through EGL, enabled by the `egl` feature.
+//!
+//! Creates a context without a window, for rendering on servers or CI machines
+//! without a display, such as with Mesa's llvmpipe.
+//! A surfaceless context is used when `EGL_MESA_platform_surfaceless` and
+//! `EGL_KHR_surfaceless_context` are available, otherwise a small pbuffer surface.
+
+#![allow(non_camel_case_types)]
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+
+use shader_version::OpenGL;
+
+use crate::gl;
+use crate::error::Error;
+use crate::{GlGraphics, RenderTarget, TextureSettings};
+
+type EGLBoolean = u32;
+type EGLenum = u32;
+type EGLint = i32;
+type EGLDisplay = *mut c_void;
+type EGLConfig = *mut c_void;
+type EGLContext = *mut c_void;
+type EGLSurface = *mut c_void;
+
+const EGL_FALSE: EGLBoolean = 0;
+const EGL_ALPHA_SIZE: EGLint = 0x3021;
+const EGL_BLUE_SIZE: EGLint = 0x3022;
+const EGL_GREEN_SIZE: EGLint = 0x3023;
+const EGL_RED_SIZE: EGLint = 0x3024;
+const EGL_STENCIL_SIZE: EGLint = 0x3026;
+const EGL_SURFACE_TYPE: EGLint = 0x3033;
+const EGL_NONE: EGLint = 0x3038;
+const EGL_RENDERABLE_TYPE: EGLint = 0x3040;
+const EGL_EXTENSIONS: EGLint = 0x3055;
+const EGL_HEIGHT: EGLint = 0x3056;
+const EGL_WIDTH: EGLint = 0x3057;
+const EGL_CONTEXT_CLIENT_VERSION: EGLint = 0x3098;
+const EGL_OPENGL_ES_API: EGLenum = 0x30A0;
+const EGL_PBUFFER_BIT: EGLint = 0x0001;
+const EGL_OPENGL_ES2_BIT: EGLint = 0x0004;
+const EGL_OPENGL_ES3_BIT: EGLint = 0x0040;
+const EGL_PLATFORM_SURFACELESS_MESA: EGLenum = 0x31DD;
+
+type GetPlatformDisplayEXT = extern "C" fn(EGLenum, *mut c_void, *const EGLint) -> EGLDisplay;
+
+#[link(name = "EGL")]
+extern "C" {
+    fn eglGetDisplay(display_id: *mut c_void) -> EGLDisplay;
+    fn eglInitialize(display: EGLDisplay, major: *mut EGLint, minor: *mut EGLint) -> EGLBoolean;
+    fn eglTerminate(display: EGLDisplay) -> EGLBoolean;
+    fn eglQueryString(display: EGLDisplay, name: EGLint) -> *const c_char;
+    fn eglBindAPI(api: EGLenum) -> EGLBoolean;
+    fn eglChooseConfig(display: EGLDisplay,
+                       attrib_list: *const EGLint,
+                       configs: *mut EGLConfig,
+                       config_size: EGLint,
+                       num_config: *mut EGLint)
+                       -> EGLBoolean;
+    fn eglCreateContext(display: EGLDisplay,
+                        config: EGLConfig,
+                        share_context: EGLContext,
+                        attrib_list: *const EGLint)
+                        -> EGLContext;
+    fn eglDestroyContext(display: EGLDisplay, context: EGLContext) -> EGLBoolean;
+    fn eglCreatePbufferSurface(display: EGLDisplay,
+                               config: EGLConfig,
+                               attrib_list: *const EGLint)
+                               -> EGLSurface;
+    fn eglDestroySurface(display: EGLDisplay, surface: EGLSurface) -> EGLBoolean;
+    fn eglMakeCurrent(display: EGLDisplay,
+                      draw: EGLSurface,
+                      read: EGLSurface,
+                      context: EGLContext)
+                      -> EGLBoolean;
+    fn eglGetProcAddress(procname: *const c_char) -> *const c_void;
+    fn eglGetError() -> EGLint;
+}
+
+// Describes the last EGL error.
+fn egl_error(call: &str) -> Error {
+    Error::Context(format!("{} failed with EGL error 0x{:X}", call, unsafe { eglGetError() }))
+}
+
+fn has_extension(extensions: *const c_char, name: &str) -> bool {
+    if extensions.is_null() {
+        return false;
+    }
+    let extensions = unsafe { CStr::from_ptr(extensions) }.to_string_lossy();
+    extensions.split(' ').any(|ext| ext == name)
+}
+
+// Opens the surfaceless platform of Mesa, or the default display.
+unsafe fn get_display() -> EGLDisplay {
+    let client_extensions = eglQueryString(ptr::null_mut(), EGL_EXTENSIONS);
+    if has_extension(client_extensions, "EGL_MESA_platform_surfaceless") {
+        let name = CString::new("eglGetPlatformDisplayEXT").unwrap();
+        let get_platform_display = eglGetProcAddress(name.as_ptr());
+        if !get_platform_display.is_null() {
+            let get_platform_display: GetPlatformDisplayEXT =
+                ::std::mem::transmute(get_platform_display);
+            let display = get_platform_display(EGL_PLATFORM_SURFACELESS_MESA,
+                                               ptr::null_mut(),
+                                               ptr::null());
+            if !display.is_null() {
+                return display;
+            }
+        }
+    }
+    eglGetDisplay(ptr::null_mut())
+}
+
+/// An OpenGL ES context without a window.
+///
+/// The context is current on the thread that created it, until dropped.
+pub struct EglContext {
+    display: EGLDisplay,
+    context: EGLContext,
+    // Null for surfaceless contexts.
+    surface: EGLSurface,
+}
+
+impl EglContext {
+    /// Creates an OpenGL ES context of the given major version, 2 or 3,
+    /// makes it current and loads the `gl` function pointers.
+    pub fn new(major_version: u32) -> Result<Self, Error> {
+        let renderable = match major_version {
+            2 => EGL_OPENGL_ES2_BIT,
+            3 => EGL_OPENGL_ES3_BIT,
+            _ => {
+                return Err(Error::Unsupported(format!("OpenGL ES {} is not supported",
+                                                      major_version)))
+            }
+        };
+        unsafe {
+            let display = get_display();
+            if display.is_null() {
+                return Err(egl_error("eglGetDisplay"));
+            }
+            let (mut major, mut minor) = (0, 0);
+            if eglInitialize(display, &mut major, &mut minor) == EGL_FALSE {
+                return Err(egl_error("eglInitialize"));
+            }
+            // From here on, dropping the context cleans up.
+            let mut egl = EglContext {
+                display: display,
+                context: ptr::null_mut(),
+                surface: ptr::null_mut(),
+            };
+            if eglBindAPI(EGL_OPENGL_ES_API) == EGL_FALSE {
+                return Err(egl_error("eglBindAPI"));
+            }
+
+            let config_attributes = [EGL_SURFACE_TYPE, EGL_PBUFFER_BIT,
+                                     EGL_RENDERABLE_TYPE, renderable,
+                                     EGL_RED_SIZE, 8,
+                                     EGL_GREEN_SIZE, 8,
+                                     EGL_BLUE_SIZE, 8,
+                                     EGL_ALPHA_SIZE, 8,
+                                     EGL_STENCIL_SIZE, 8,
+                                     EGL_NONE];
+            let mut config = ptr::null_mut();
+            let mut configs = 0;
+            if eglChooseConfig(display, config_attributes.as_ptr(), &mut config, 1, &mut configs) ==
+               EGL_FALSE || configs == 0 {
+                return Err(egl_error("eglChooseConfig"));
+            }
+
+            let context_attributes = [EGL_CONTEXT_CLIENT_VERSION, major_version as EGLint, EGL_NONE];
+            egl.context = eglCreateContext(display,
+                                           config,
+                                           ptr::null_mut(),
+                                           context_attributes.as_ptr());
+            if egl.context.is_null() {
+                return Err(egl_error("eglCreateContext"));
+            }
+
+            let extensions = eglQueryString(display, EGL_EXTENSIONS);
+            if !has_extension(extensions, "EGL_KHR_surfaceless_context") {
+                let surface_attributes = [EGL_WIDTH, 1, EGL_HEIGHT, 1, EGL_NONE];
+                egl.surface = eglCreatePbufferSurface(display, config, surface_attributes.as_ptr());
+                if egl.surface.is_null() {
+                    return Err(egl_error("eglCreatePbufferSurface"));
+                }
+            }
+            egl.make_current()?;
+
+            gl::load_with(|name| {
+                let name = CString::new(name).unwrap();
+                eglGetProcAddress(name.as_ptr())
+            });
+            Ok(egl)
+        }
+    }
+
+    /// Makes the context current on the calling thread.
+    pub fn make_current(&self) -> Result<(), Error> {
+        if unsafe { eglMakeCurrent(self.display, self.surface, self.surface, self.context) } ==
+           EGL_FALSE {
+            return Err(egl_error("eglMakeCurrent"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EglContext {
+    fn drop(&mut self) {
+        unsafe {
+            eglMakeCurrent(self.display, ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+            if !self.surface.is_null() {
+                eglDestroySurface(self.display, self.surface);
+            }
+            if !self.context.is_null() {
+                eglDestroyContext(self.display, self.context);
+            }
+            eglTerminate(self.display);
+        }
+    }
+}
+
+/// A headless context with a back-end and a render target to draw into.
+///
+/// The fields are dropped in order, releasing the OpenGL objects before the context.
+pub struct Headless {
+    /// The back-end.
+    pub graphics: GlGraphics,
+    /// A render target with a stencil buffer, for drawing with `GlGraphics::draw_to`.
+    pub target: RenderTarget,
+    /// The context.
+    pub context: EglContext,
+}
+
+impl Headless {
+    /// Creates a headless OpenGL ES 3 context, falling back to OpenGL ES 2,
+    /// with a `width` x `height` render target.
+    pub fn new(width: u32, height: u32, opengl: OpenGL) -> Result<Self, Error> {
+        let context = match EglContext::new(3) {
+            Ok(context) => context,
+            Err(_) => EglContext::new(2)?,
+        };
+        let graphics = GlGraphics::try_new(opengl)?;
+        let target = RenderTarget::with_stencil(width, height, &TextureSettings::new())?;
+        Ok(Headless {
+            graphics: graphics,
+            target: target,
+            context: context,
+        })
+    }
+}
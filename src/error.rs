@@ -1,22 +1,121 @@
 //! Errors
 
-use std::fmt;
+use std::{error, fmt, io};
+use image::ImageError;
+use rusttype;
+use crate::gl;
+use crate::gl::types::GLenum;
 
 /// An enum to represent various possible run-time errors that may occur.
 #[derive(Debug)]
 pub enum Error {
     /// An error happened with I/O.
-    IoError(::std::io::Error),
+    IoError(io::Error),
+    /// An image could not be decoded.
+    Image(ImageError),
+    /// Font data could not be parsed.
+    InvalidFont(rusttype::Error),
+    /// A font collection has no font at the index.
+    FontNotInCollection(usize),
+    /// A shader failed to compile, with the info log.
+    ShaderCompile(String),
+    /// A shader program failed to link, with the info log.
+    ProgramLink(String),
+    /// An attribute was not found in a shader program.
+    MissingAttribute(String),
+    /// A uniform was not found in a shader program.
+    MissingUniform(String),
+    /// An OpenGL function pointer has not been loaded.
+    FunctionNotLoaded(&'static str),
+    /// OpenGL reported an error code, after the named operation.
+    GlError(GLenum, String),
+    /// An OpenGL context could not be created.
+    Context(String),
+    /// A framebuffer object is incomplete, with its status.
+    FramebufferIncomplete(GLenum),
+    /// A pixel format is not supported by the OpenGL context.
+    UnsupportedFormat(String),
+    /// A feature is not supported by the OpenGL context.
+    Unsupported(String),
+    /// Data does not match what was expected, such as a malformed file.
+    InvalidData(String),
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        match *self {
+            Error::IoError(ref err) => write!(f, "I/O error: {}", err),
+            Error::Image(ref err) => write!(f, "Could not decode image: {}", err),
+            Error::InvalidFont(ref err) => write!(f, "Invalid font: {}", err),
+            Error::FontNotInCollection(index) => {
+                write!(f, "There is no font at index {} in the collection", index)
+            }
+            Error::ShaderCompile(ref log) => write!(f, "Shader compilation failed: {}", log),
+            Error::ProgramLink(ref log) => write!(f, "Program linking failed: {}", log),
+            Error::MissingAttribute(ref name) => {
+                write!(f, "Attribute '{}' does not exist in shader", name)
+            }
+            Error::MissingUniform(ref name) => {
+                write!(f, "Uniform '{}' does not exist in shader", name)
+            }
+            Error::FunctionNotLoaded(name) => {
+                write!(f, "OpenGL function '{}' is not loaded", name)
+            }
+            Error::GlError(code, ref call) => {
+                write!(f, "OpenGL error 0x{:X} after {}", code, call)
+            }
+            Error::FramebufferIncomplete(status) => {
+                write!(f, "Framebuffer is incomplete, status: 0x{:X}", status)
+            }
+            Error::Context(ref msg) => write!(f, "Could not create a context: {}", msg),
+            Error::UnsupportedFormat(ref msg) |
+            Error::Unsupported(ref msg) |
+            Error::InvalidData(ref msg) => write!(f, "{}", msg),
+        }
     }
 }
 
-impl From<::std::io::Error> for Error {
-    fn from(err: ::std::io::Error) -> Error {
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IoError(ref err) => Some(err),
+            Error::Image(ref err) => Some(err),
+            Error::InvalidFont(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
         Error::IoError(err)
     }
 }
+
+impl From<ImageError> for Error {
+    fn from(err: ImageError) -> Error {
+        Error::Image(err)
+    }
+}
+
+impl From<rusttype::Error> for Error {
+    fn from(err: rusttype::Error) -> Error {
+        Error::InvalidFont(err)
+    }
+}
+
+/// Returns `Err` if OpenGL reports an error after `call`.
+pub(crate) fn check_gl_error(call: &str) -> Result<(), Error> {
+    // With `gl-debug`, the checked calls have already consumed the error.
+    #[cfg(feature = "gl-debug")]
+    {
+        let code = crate::debug::take_error();
+        if code != gl::NO_ERROR {
+            return Err(Error::GlError(code, call.to_string()));
+        }
+    }
+    match unsafe { gl::GetError() } {
+        gl::NO_ERROR => Ok(()),
+        code => Err(Error::GlError(code, call.to_string())),
+    }
+}
@@ -2,8 +2,12 @@
 
 use {rusttype, graphics};
 use crate::{Texture, TextureSettings};
+use std::cell::RefCell;
 use std::collections::HashMap;
-use graphics::types::Scalar;
+use std::rc::Rc;
+use graphics::types::{Color, Scalar};
+use graphics::math::Matrix2d;
+use graphics::{DrawState, Graphics, Image};
 
 extern crate fnv;
 use self::fnv::FnvHasher;
@@ -20,16 +24,162 @@ use graphics::character::CharacterCache;
 /// The type alias for font characters.
 pub type Character<'a> = graphics::character::Character<'a, Texture>;
 
+// Size of a newly allocated atlas page.
+const INITIAL_PAGE_SIZE: u32 = 256;
+// Pages double in size up to this limit before a new page is started.
+const MAX_PAGE_SIZE: u32 = 2048;
+// Empty pixels kept between glyphs to avoid bleeding when filtering.
+const GLYPH_PADDING: u32 = 1;
+
+/// A glyph stored in one of the atlas pages of a `GlyphCache`.
+#[derive(Clone, Copy)]
+pub struct AtlasGlyph<'a> {
+    /// The offset of the glyph image relative to the pen position.
+    pub offset: [Scalar; 2],
+    /// The distance to move the pen after drawing the glyph.
+    pub advance_size: [Scalar; 2],
+    /// The atlas page containing the glyph.
+    pub texture: &'a Texture,
+    /// The rectangle `[x, y, w, h]` of the glyph within `texture`, in pixels.
+    pub src_rect: [Scalar; 4],
+}
+
+// A row of glyphs in an atlas page.
+struct Shelf {
+    y: u32,
+    height: u32,
+    // The next free column.
+    x: u32,
+}
+
+// A texture shared by many glyphs, packed in shelves.
+struct AtlasPage {
+    texture: Texture,
+    // The coverage values, kept to rebuild the texture when growing
+    // and shared with the texture to reload it after the context is lost.
+    pixels: Rc<RefCell<Vec<u8>>>,
+    size: u32,
+    shelves: Vec<Shelf>,
+}
+
+// Creates the texture of a page, which reloads from the current pixels.
+fn page_texture(pixels: &Rc<RefCell<Vec<u8>>>,
+                size: u32,
+                settings: &TextureSettings)
+                -> Result<Texture, Error> {
+    let mut texture = Texture::from_memory_alpha(&pixels.borrow(), size, size, settings)?;
+    let pixels = pixels.clone();
+    let settings = *settings;
+    texture.set_reload(move || {
+        Texture::from_memory_alpha(&pixels.borrow(), size, size, &settings)
+    });
+    Ok(texture)
+}
+
+impl AtlasPage {
+    fn new(size: u32, settings: &TextureSettings) -> Result<Self, Error> {
+        let pixels = Rc::new(RefCell::new(vec![0; (size * size) as usize]));
+        let texture = page_texture(&pixels, size, settings)?;
+        Ok(AtlasPage {
+            texture: texture,
+            pixels: pixels,
+            size: size,
+            shelves: Vec::new(),
+        })
+    }
+
+    /// Reserves room for a `w` x `h` image, returning its top-left corner.
+    fn allocate(&mut self, w: u32, h: u32) -> Option<[u32; 2]> {
+        let (w, h) = (w + 2 * GLYPH_PADDING, h + 2 * GLYPH_PADDING);
+
+        // Pick the lowest shelf the image fits in.
+        let mut best: Option<usize> = None;
+        for (i, shelf) in self.shelves.iter().enumerate() {
+            if shelf.height >= h && self.size - shelf.x >= w &&
+               best.map_or(true, |b| self.shelves[b].height > shelf.height) {
+                best = Some(i);
+            }
+        }
+
+        // Prefer opening a new shelf over wasting most of a tall one.
+        let top = self.shelves.last().map_or(0, |s| s.y + s.height);
+        let fits_new_shelf = w <= self.size && top + h <= self.size;
+        let index = match best {
+            Some(i) if !fits_new_shelf || self.shelves[i].height <= h + h / 2 => i,
+            _ if fits_new_shelf => {
+                self.shelves.push(Shelf { y: top, height: h, x: 0 });
+                self.shelves.len() - 1
+            }
+            _ => return None,
+        };
+
+        let shelf = &mut self.shelves[index];
+        let pos = [shelf.x + GLYPH_PADDING, shelf.y + GLYPH_PADDING];
+        shelf.x += w;
+        Some(pos)
+    }
+
+    /// Doubles the size of the page, keeping the existing glyphs in place.
+    fn grow(&mut self, settings: &TextureSettings) -> Result<(), Error> {
+        let old_size = self.size as usize;
+        let size = self.size * 2;
+        let mut pixels = vec![0; (size * size) as usize];
+        {
+            let old_pixels = self.pixels.borrow();
+            for row in 0..old_size {
+                let dst = row * size as usize;
+                pixels[dst..dst + old_size]
+                    .copy_from_slice(&old_pixels[row * old_size..(row + 1) * old_size]);
+            }
+        }
+        // The old texture keeps its own pixels while regions of it are still drawn.
+        let pixels = Rc::new(RefCell::new(pixels));
+        self.texture = page_texture(&pixels, size, settings)?;
+        self.pixels = pixels;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Copies a `w` x `h` coverage image to `pos`.
+    fn write(&mut self, pos: [u32; 2], w: u32, h: u32, image: &[u8]) -> Result<(), Error> {
+        {
+            let mut pixels = self.pixels.borrow_mut();
+            for row in 0..h as usize {
+                let src = row * w as usize;
+                let dst = (pos[1] as usize + row) * self.size as usize + pos[0] as usize;
+                pixels[dst..dst + w as usize].copy_from_slice(&image[src..src + w as usize]);
+            }
+        }
+        self.texture.update_region(image, pos, [w, h])
+    }
+}
+
+// A rasterized glyph and where to find it in the atlas.
+struct GlyphEntry {
+    offset: [Scalar; 2],
+    advance_size: [Scalar; 2],
+    page: usize,
+    rect: [u32; 4],
+    // The region of the page texture, for `CharacterCache`.
+    texture: Texture,
+}
+
 /// A struct used for caching rendered font.
+///
+/// Glyphs are packed into a few shared atlas pages, so text only binds
+/// a handful of textures, whether drawn with `draw_text` or `graphics::Text`.
+/// The textures of `CharacterCache` characters are regions of the pages.
 pub struct GlyphCache<'a> {
     /// The font.
     pub font: rusttype::Font<'a>,
     /// The settings to render the font with.
     settings: TextureSettings,
-    // Maps from fontsize and character to offset, size and texture.
+    // Maps from fontsize and character to the glyph metrics and atlas location.
     data: HashMap<(FontSize, char),
-                  ([Scalar; 2], [Scalar; 2], Texture),
+                  GlyphEntry,
                   BuildHasherDefault<FnvHasher>>,
+    // The atlas pages holding the rasterized glyphs.
+    pages: Vec<AtlasPage>,
 }
 
 impl<'a> GlyphCache<'a> {
@@ -40,6 +190,7 @@ impl<'a> GlyphCache<'a> {
             font: font,
             settings: settings,
             data: HashMap::with_hasher(fnv),
+            pages: Vec::new(),
         }
     }
 
@@ -47,53 +198,212 @@ impl<'a> GlyphCache<'a> {
     pub fn new<P>(font: P, settings: TextureSettings) -> Result<GlyphCache<'static>, Error>
         where P: AsRef<Path>
     {
-        let fnv = BuildHasherDefault::<FnvHasher>::default();
         let mut file = File::open(font)?;
         let mut file_buffer = Vec::new();
         file.read_to_end(&mut file_buffer)?;
 
-        let collection = rusttype::FontCollection::from_bytes(file_buffer).unwrap();
-        let font = collection.into_font().unwrap();
-        Ok(GlyphCache {
-            font: font,
-            settings: settings,
-            data: HashMap::with_hasher(fnv),
-        })
+        let collection = rusttype::FontCollection::from_bytes(file_buffer)?;
+        let font = collection.into_font()?;
+        Ok(GlyphCache::from_font(font, settings))
     }
 
     /// Creates a GlyphCache for a font stored in memory.
     pub fn from_bytes(font: &'a [u8], settings: TextureSettings) -> Result<GlyphCache<'a>, Error> {
-        let collection = rusttype::FontCollection::from_bytes(font).unwrap();
-        let font = collection.into_font().unwrap();
+        let collection = rusttype::FontCollection::from_bytes(font)?;
+        let font = collection.into_font()?;
+        Ok(Self::from_font(font, settings))
+    }
+
+    /// Creates a GlyphCache for the font at `index` in a font collection stored in memory.
+    pub fn from_bytes_at(font: &'a [u8],
+                         index: usize,
+                         settings: TextureSettings)
+                         -> Result<GlyphCache<'a>, Error> {
+        let collection = rusttype::FontCollection::from_bytes(font)?;
+        let font = match collection.font_at(index) {
+            Ok(font) => font,
+            Err(rusttype::Error::CollectionIndexOutOfBounds) => {
+                return Err(Error::FontNotInCollection(index))
+            }
+            Err(err) => return Err(err.into()),
+        };
         Ok(Self::from_font(font, settings))
     }
 
     /// Load all characters in the `chars` iterator for `size`
-    pub fn preload_chars<I>(&mut self, size: FontSize, chars: I)
+    pub fn preload_chars<I>(&mut self, size: FontSize, chars: I) -> Result<(), Error>
         where I: Iterator<Item = char>
     {
         for ch in chars {
-            self.character(size, ch);
+            self.load(size, ch)?;
         }
+        Ok(())
     }
 
     /// Load all the printable ASCII characters for `size`. Includes space.
-    pub fn preload_printable_ascii(&mut self, size: FontSize) {
+    pub fn preload_printable_ascii(&mut self, size: FontSize) -> Result<(), Error> {
         // [0x20, 0x7F) contains all printable ASCII characters ([' ', '~'])
-        self.preload_chars(size, (0x20u8..0x7F).map(|ch| ch as char));
+        self.preload_chars(size, (0x20u8..0x7F).map(|ch| ch as char))
     }
 
     /// Return `ch` for `size` if it's already cached. Don't load.
     /// See the `preload_*` functions.
     pub fn opt_character(&self, size: FontSize, ch: char) -> Option<Character> {
-        self.data.get(&(size, ch)).map(|&(offset, size, ref texture)| {
+        self.data.get(&(pixel_size(size), ch)).map(|entry| {
             Character {
-                offset: offset,
-                size: size,
-                texture: texture,
+                offset: entry.offset,
+                size: entry.advance_size,
+                texture: &entry.texture,
             }
         })
     }
+
+    /// Return the atlas glyph of `ch` for `size` if it's already cached. Don't load.
+    pub fn opt_glyph(&self, size: FontSize, ch: char) -> Option<AtlasGlyph> {
+        self.data.get(&(pixel_size(size), ch)).map(|entry| self.atlas_glyph(entry))
+    }
+
+    /// Returns the atlas glyph of `ch` for `size`, loading it if necessary.
+    pub fn glyph(&mut self, size: FontSize, ch: char) -> Result<AtlasGlyph, Error> {
+        let key = self.load(size, ch)?;
+        Ok(self.atlas_glyph(&self.data[&key]))
+    }
+
+    /// Draws `text` from the atlas pages, with the pen starting at the origin of `transform`.
+    ///
+    /// Consecutive glyphs on the same page share a texture,
+    /// which lets the back-end batch them into a single draw call.
+    pub fn draw_text<G>(&mut self,
+                        text: &str,
+                        size: FontSize,
+                        color: Color,
+                        draw_state: &DrawState,
+                        transform: Matrix2d,
+                        g: &mut G)
+                        -> Result<(), Error>
+        where G: Graphics<Texture = Texture>
+    {
+        let image = Image::new_color(color);
+        let mut pos = [0.0, 0.0];
+        for ch in text.chars() {
+            let glyph = self.glyph(size, ch)?;
+            let src_rect = glyph.src_rect;
+            image.src_rect(src_rect)
+                .rect([pos[0] + glyph.offset[0],
+                       pos[1] - glyph.offset[1],
+                       src_rect[2],
+                       src_rect[3]])
+                .draw(glyph.texture, draw_state, transform, g);
+            pos[0] += glyph.advance_size[0];
+            pos[1] += glyph.advance_size[1];
+        }
+        Ok(())
+    }
+
+    fn atlas_glyph(&self, entry: &GlyphEntry) -> AtlasGlyph {
+        let rect = entry.rect;
+        AtlasGlyph {
+            offset: entry.offset,
+            advance_size: entry.advance_size,
+            texture: &self.pages[entry.page].texture,
+            src_rect: [rect[0] as Scalar, rect[1] as Scalar, rect[2] as Scalar, rect[3] as Scalar],
+        }
+    }
+
+    /// Rasterizes `ch` into the atlas unless it is already there, returning its key.
+    fn load(&mut self, size: FontSize, ch: char) -> Result<(FontSize, char), Error> {
+        use rusttype as rt;
+
+        let size = pixel_size(size);
+        if self.data.contains_key(&(size, ch)) {
+            return Ok((size, ch));
+        }
+
+        // this is only None for invalid GlyphIds,
+        // but char is converted to a Codepoint which must result in a glyph.
+        let glyph = self.font.glyph(ch);
+        let scale = rt::Scale::uniform(size as f32);
+        let mut glyph = glyph.scaled(scale);
+
+        // some fonts do not contain glyph zero as fallback, instead try U+FFFD.
+        if glyph.id() == rt::GlyphId(0) && glyph.shape().is_none() {
+            glyph = self.font.glyph('\u{FFFD}').scaled(scale);
+        }
+
+        let h_metrics = glyph.h_metrics();
+        let bounding_box = glyph.exact_bounding_box().unwrap_or(rt::Rect {
+            min: rt::Point { x: 0.0, y: 0.0 },
+            max: rt::Point { x: 0.0, y: 0.0 },
+        });
+        let glyph = glyph.positioned(rt::point(0.0, 0.0));
+        let pixel_bounding_box = glyph.pixel_bounding_box().unwrap_or(rt::Rect {
+            min: rt::Point { x: 0, y: 0 },
+            max: rt::Point { x: 0, y: 0 },
+        });
+        let pixel_bb_width = pixel_bounding_box.width() + 2;
+        let pixel_bb_height = pixel_bounding_box.height() + 2;
+
+        let mut image_buffer = Vec::<u8>::new();
+        image_buffer.resize((pixel_bb_width * pixel_bb_height) as usize, 0);
+        glyph.draw(|x, y, v| {
+            let pos = ((x + 1) + (y + 1) * (pixel_bb_width as u32)) as usize;
+            image_buffer[pos] = (255.0 * v) as u8;
+        });
+
+        let (w, h) = (pixel_bb_width as u32, pixel_bb_height as u32);
+        let (page, pos) = self.allocate(w, h)?;
+        self.pages[page].write(pos, w, h, &image_buffer)?;
+        let rect = [pos[0], pos[1], w, h];
+
+        self.data.insert((size, ch), GlyphEntry {
+            offset: [bounding_box.min.x as Scalar - 1.0,
+                     -pixel_bounding_box.min.y as Scalar + 1.0],
+            advance_size: [h_metrics.advance_width as Scalar, 0 as Scalar],
+            page: page,
+            rect: rect,
+            texture: self.pages[page].texture.region(rect),
+        });
+        Ok((size, ch))
+    }
+
+    /// Finds room for a `w` x `h` image, growing the last page or starting a new one.
+    fn allocate(&mut self, w: u32, h: u32) -> Result<(usize, [u32; 2]), Error> {
+        for (i, page) in self.pages.iter_mut().enumerate() {
+            if let Some(pos) = page.allocate(w, h) {
+                return Ok((i, pos));
+            }
+        }
+        if let Some(last) = self.pages.len().checked_sub(1) {
+            while self.pages[last].size < MAX_PAGE_SIZE {
+                self.pages[last].grow(&self.settings)?;
+                // Regions of the replaced texture are created again from the new one.
+                let texture = &self.pages[last].texture;
+                for entry in self.data.values_mut() {
+                    if entry.page == last {
+                        entry.texture = texture.region(entry.rect);
+                    }
+                }
+                if let Some(pos) = self.pages[last].allocate(w, h) {
+                    return Ok((last, pos));
+                }
+            }
+        }
+
+        // Glyphs larger than the page limit get a page of their own.
+        let mut size = INITIAL_PAGE_SIZE;
+        while size < w.max(h) + 2 * GLYPH_PADDING {
+            size *= 2;
+        }
+        let mut page = AtlasPage::new(size, &self.settings)?;
+        let pos = page.allocate(w, h).expect("page is large enough for the glyph");
+        self.pages.push(page);
+        Ok((self.pages.len() - 1, pos))
+    }
+}
+
+/// Converts points to pixels.
+fn pixel_size(size: FontSize) -> FontSize {
+    ((size as f32) * 1.333).round() as u32
 }
 
 impl<'b> CharacterCache for GlyphCache<'b> {
@@ -101,76 +411,89 @@ impl<'b> CharacterCache for GlyphCache<'b> {
     type Error = Error;
 
     fn character<'a>(&'a mut self, size: FontSize, ch: char) -> Result<Character<'a>, Error> {
-        use std::collections::hash_map::Entry;
-        use rusttype as rt;
+        let key = self.load(size, ch)?;
+        let entry = &self.data[&key];
+        Ok(Character {
+            offset: entry.offset,
+            size: entry.advance_size,
+            texture: &entry.texture,
+        })
+    }
+}
 
-        let size = ((size as f32) * 1.333).round() as u32; // convert points to pixels
-
-        match self.data.entry((size, ch)) {
-            //returning `into_mut()' to get reference with 'a lifetime
-            Entry::Occupied(v) => {
-                let &mut (offset, size, ref texture) = v.into_mut();
-                Ok(
-                    Character {
-                        offset: offset,
-                        size: size,
-                        texture: texture,
-                    }
-                )
-            }
-            Entry::Vacant(v) => {
-                // this is only None for invalid GlyphIds,
-                // but char is converted to a Codepoint which must result in a glyph.
-                let glyph = self.font.glyph(ch);
-                let scale = rt::Scale::uniform(size as f32);
-                let mut glyph = glyph.scaled(scale);
-
-                // some fonts do not contain glyph zero as fallback, instead try U+FFFD.
-                if glyph.id() == rt::GlyphId(0) && glyph.shape().is_none() {
-                    glyph = self.font.glyph('\u{FFFD}').scaled(scale);
-                }
+#[test]
+fn test_atlas_page_allocate() {
+    use crate::testing::MockGl;
 
-                let h_metrics = glyph.h_metrics();
-                let bounding_box = glyph.exact_bounding_box().unwrap_or(rt::Rect {
-                    min: rt::Point { x: 0.0, y: 0.0 },
-                    max: rt::Point { x: 0.0, y: 0.0 },
-                });
-                let glyph = glyph.positioned(rt::point(0.0, 0.0));
-                let pixel_bounding_box = glyph.pixel_bounding_box().unwrap_or(rt::Rect {
-                    min: rt::Point { x: 0, y: 0 },
-                    max: rt::Point { x: 0, y: 0 },
-                });
-                let pixel_bb_width = pixel_bounding_box.width() + 2;
-                let pixel_bb_height = pixel_bounding_box.height() + 2;
-
-                let mut image_buffer = Vec::<u8>::new();
-                image_buffer.resize((pixel_bb_width * pixel_bb_height) as usize, 0);
-                glyph.draw(|x, y, v| {
-                    let pos = ((x + 1) + (y + 1) * (pixel_bb_width as u32)) as usize;
-                    image_buffer[pos] = (255.0 * v) as u8;
-                });
-
-                let &mut (offset, size, ref texture) =
-                    v.insert(([bounding_box.min.x as Scalar - 1.0,
-                               -pixel_bounding_box.min.y as Scalar + 1.0],
-                              [h_metrics.advance_width as Scalar, 0 as Scalar],
-                              {
-                                  if pixel_bb_width == 0 || pixel_bb_height == 0 {
-                                      Texture::empty().unwrap()
-                                  } else {
-                                      Texture::from_memory_alpha(&image_buffer,
-                                                                 pixel_bb_width as u32,
-                                                                 pixel_bb_height as u32,
-                                                                 &self.settings)
-                                          .unwrap()
-                                  }
-                              }));
-                Ok(Character {
-                    offset: offset,
-                    size: size,
-                    texture: texture,
-                })
-            }
-        }
+    let _mock = MockGl::new();
+    let mut page = AtlasPage::new(64, &TextureSettings::new()).unwrap();
+    // Glyphs of similar height share a shelf, left to right.
+    assert_eq!(page.allocate(10, 10), Some([1, 1]));
+    assert_eq!(page.allocate(10, 9), Some([13, 1]));
+    // Much shorter glyphs open a new shelf below.
+    assert_eq!(page.allocate(4, 4), Some([1, 13]));
+    assert_eq!(page.shelves.len(), 2);
+    // Full shelves move on to a new one.
+    assert_eq!(page.allocate(38, 10), Some([25, 1]));
+    assert_eq!(page.allocate(10, 10), Some([1, 19]));
+    assert_eq!(page.shelves.len(), 3);
+    // Glyphs wider than the page never fit.
+    assert_eq!(page.allocate(63, 1), None);
+}
+
+#[test]
+fn test_atlas_page_grow() {
+    use graphics::ImageSize;
+    use crate::testing::MockGl;
+
+    let _mock = MockGl::new();
+    let settings = TextureSettings::new();
+    let mut page = AtlasPage::new(8, &settings).unwrap();
+    let pos = page.allocate(2, 2).unwrap();
+    page.write(pos, 2, 2, &[1, 2, 3, 4]).unwrap();
+    assert_eq!(page.allocate(8, 8), None);
+
+    page.grow(&settings).unwrap();
+    assert_eq!(page.size, 16);
+    assert_eq!(page.texture.get_size(), (16, 16));
+    let pixels = page.pixels.borrow();
+    assert_eq!(&pixels[16 + 1..16 + 3], &[1, 2]);
+    assert_eq!(&pixels[32 + 1..32 + 3], &[3, 4]);
+    drop(pixels);
+    // Existing shelves stay in place, and the new room is used.
+    assert_eq!(page.allocate(8, 8), Some([1, 5]));
+}
+
+#[test]
+fn test_preloaded_characters() {
+    use graphics::ImageSize;
+    use crate::testing::MockGl;
+
+    let _mock = MockGl::new();
+    let mut cache = GlyphCache::new("assets/DejaVuSansMono.ttf", TextureSettings::new()).unwrap();
+    assert!(cache.opt_character(12, 'a').is_none());
+    cache.preload_printable_ascii(12).unwrap();
+
+    let glyph = cache.opt_glyph(12, 'a').unwrap();
+    let (w, h) = (glyph.src_rect[2] as u32, glyph.src_rect[3] as u32);
+    let page_id = glyph.texture.get_id();
+    let character = cache.opt_character(12, 'a').unwrap();
+    assert_eq!(character.texture.get_size(), (w, h));
+    assert_eq!(character.texture.get_id(), page_id);
+}
+
+#[test]
+fn test_regions_follow_grown_page() {
+    use crate::testing::MockGl;
+
+    let _mock = MockGl::new();
+    let mut cache = GlyphCache::new("assets/DejaVuSansMono.ttf", TextureSettings::new()).unwrap();
+    cache.preload_chars(12, "a".chars()).unwrap();
+    // Large glyphs fill the first page, which grows.
+    for size in 40..60 {
+        cache.preload_printable_ascii(size).unwrap();
     }
+    assert!(cache.pages[0].size > INITIAL_PAGE_SIZE);
+    let page_id = cache.pages[0].texture.get_id();
+    assert_eq!(cache.opt_character(12, 'a').unwrap().texture.get_id(), page_id);
 }
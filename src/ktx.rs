@@ -0,0 +1,261 @@
+//! Parsing of KTX 1 and KTX 2 texture containers.
+//!
+//! KTX 1 files store the OpenGL internal format directly, which covers ETC1.
+//! KTX 2 files store a `VkFormat`, of which ETC2, EAC and ASTC are supported.
+
+use crate::gl;
+use crate::gl::types::GLenum;
+use crate::error::Error;
+
+const KTX1_IDENTIFIER: [u8; 12] =
+    [0xAB, 0x4B, 0x54, 0x58, 0x20, 0x31, 0x31, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+const KTX2_IDENTIFIER: [u8; 12] =
+    [0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+
+// `KHR_texture_compression_astc_ldr`, from 4x4 to 12x12 blocks.
+const COMPRESSED_RGBA_ASTC_4X4_KHR: GLenum = 0x93B0;
+const COMPRESSED_SRGB8_ALPHA8_ASTC_4X4_KHR: GLenum = 0x93D0;
+
+// `VkFormat` values of the formats KTX 2 files can be uploaded with.
+const VK_FORMAT_ETC2_R8G8B8_UNORM_BLOCK: u32 = 147;
+const VK_FORMAT_EAC_R11G11_SNORM_BLOCK: u32 = 156;
+const VK_FORMAT_ASTC_4X4_UNORM_BLOCK: u32 = 157;
+const VK_FORMAT_ASTC_12X12_SRGB_BLOCK: u32 = 184;
+
+/// A compressed image read from a KTX container.
+pub struct KtxImage<'a> {
+    /// The compressed OpenGL internal format.
+    pub internal_format: GLenum,
+    /// The width of the base level.
+    pub width: u32,
+    /// The height of the base level.
+    pub height: u32,
+    /// The compressed data of each mip level, starting with the base level.
+    pub levels: Vec<&'a [u8]>,
+}
+
+/// Parses a KTX 1 or KTX 2 container holding a single compressed 2D image.
+pub fn parse(bytes: &[u8]) -> Result<KtxImage, Error> {
+    if bytes.starts_with(&KTX1_IDENTIFIER) {
+        parse_ktx1(bytes)
+    } else if bytes.starts_with(&KTX2_IDENTIFIER) {
+        parse_ktx2(bytes)
+    } else {
+        Err(Error::InvalidData("Not a KTX file".to_string()))
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize, big_endian: bool) -> Result<u32, Error> {
+    if offset + 4 > bytes.len() {
+        return Err(Error::InvalidData("Unexpected end of KTX file".to_string()));
+    }
+    let b = [bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]];
+    Ok(if big_endian {
+        (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+    } else {
+        (b[3] as u32) << 24 | (b[2] as u32) << 16 | (b[1] as u32) << 8 | b[0] as u32
+    })
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64, Error> {
+    let low = read_u32(bytes, offset, false)? as u64;
+    let high = read_u32(bytes, offset + 4, false)? as u64;
+    Ok(high << 32 | low)
+}
+
+fn slice(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], Error> {
+    match offset.checked_add(len) {
+        Some(end) if end <= bytes.len() => Ok(&bytes[offset..end]),
+        _ => Err(Error::InvalidData("Unexpected end of KTX file".to_string())),
+    }
+}
+
+fn check_2d(depth: u32, layers: u32, faces: u32) -> Result<(), Error> {
+    if depth > 1 || layers > 1 || faces != 1 {
+        Err(Error::Unsupported("Only single 2D images are supported in KTX files".to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+// Checks the level count of the header against the size of the base level,
+// and against the bytes left for `level_bytes` of each level.
+fn check_levels(levels: u32,
+                width: u32,
+                height: u32,
+                remaining: usize,
+                level_bytes: usize)
+                -> Result<(), Error> {
+    let max_levels = 32 - width.max(height).max(1).leading_zeros();
+    if levels > max_levels || levels as usize > remaining / level_bytes {
+        Err(Error::InvalidData(format!("Invalid mip level count {} in KTX header", levels)))
+    } else {
+        Ok(())
+    }
+}
+
+fn parse_ktx1(bytes: &[u8]) -> Result<KtxImage, Error> {
+    let big_endian = match read_u32(bytes, 12, false)? {
+        0x04030201 => false,
+        0x01020304 => true,
+        _ => return Err(Error::InvalidData("Invalid endianness in KTX header".to_string())),
+    };
+    let field = |index: usize| read_u32(bytes, 16 + index * 4, big_endian);
+    let gl_type = field(0)?;
+    let gl_format = field(2)?;
+    let internal_format = field(3)?;
+    let width = field(5)?;
+    let height = field(6)?;
+    let levels = field(10)?.max(1);
+    let key_value_bytes = field(11)?;
+    check_2d(field(7)?, field(8)?, field(9)?)?;
+    if gl_type != 0 || gl_format != 0 {
+        return Err(Error::UnsupportedFormat("Only compressed KTX files are supported".to_string()));
+    }
+
+    let mut offset = 64 + key_value_bytes as usize;
+    // Each level starts with its size.
+    check_levels(levels, width, height, bytes.len().saturating_sub(offset), 4)?;
+    let mut data = Vec::with_capacity(levels as usize);
+    for _ in 0..levels {
+        let size = read_u32(bytes, offset, big_endian)? as usize;
+        offset += 4;
+        data.push(slice(bytes, offset, size)?);
+        // Levels are padded to 4 bytes.
+        offset += (size + 3) & !3;
+    }
+
+    Ok(KtxImage {
+        internal_format: internal_format,
+        width: width,
+        height: height,
+        levels: data,
+    })
+}
+
+fn parse_ktx2(bytes: &[u8]) -> Result<KtxImage, Error> {
+    let field = |index: usize| read_u32(bytes, 12 + index * 4, false);
+    let vk_format = field(0)?;
+    let width = field(2)?;
+    let height = field(3)?;
+    let levels = field(7)?.max(1);
+    let supercompression = field(8)?;
+    check_2d(field(4)?, field(5)?, field(6)?)?;
+    if supercompression != 0 {
+        return Err(Error::Unsupported(format!("Unsupported KTX 2 supercompression scheme {}",
+                                              supercompression)));
+    }
+    let internal_format = match vk_format_to_gl(vk_format) {
+        Some(format) => format,
+        None => {
+            return Err(Error::UnsupportedFormat(format!("Unsupported KTX 2 format {}", vk_format)))
+        }
+    };
+
+    // The level index follows the 80 byte header, starting with the base level.
+    check_levels(levels, width, height, bytes.len().saturating_sub(80), 24)?;
+    let mut data = Vec::with_capacity(levels as usize);
+    for level in 0..levels as usize {
+        let offset = read_u64(bytes, 80 + level * 24)?;
+        let len = read_u64(bytes, 80 + level * 24 + 8)?;
+        data.push(slice(bytes, offset as usize, len as usize)?);
+    }
+
+    Ok(KtxImage {
+        internal_format: internal_format,
+        width: width,
+        height: height,
+        levels: data,
+    })
+}
+
+/// Maps a compressed `VkFormat` to the matching OpenGL internal format.
+fn vk_format_to_gl(vk_format: u32) -> Option<GLenum> {
+    match vk_format {
+        VK_FORMAT_ETC2_R8G8B8_UNORM_BLOCK..=VK_FORMAT_EAC_R11G11_SNORM_BLOCK => {
+            Some(match vk_format - VK_FORMAT_ETC2_R8G8B8_UNORM_BLOCK {
+                0 => gl::COMPRESSED_RGB8_ETC2,
+                1 => gl::COMPRESSED_SRGB8_ETC2,
+                2 => gl::COMPRESSED_RGB8_PUNCHTHROUGH_ALPHA1_ETC2,
+                3 => gl::COMPRESSED_SRGB8_PUNCHTHROUGH_ALPHA1_ETC2,
+                4 => gl::COMPRESSED_RGBA8_ETC2_EAC,
+                5 => gl::COMPRESSED_SRGB8_ALPHA8_ETC2_EAC,
+                6 => gl::COMPRESSED_R11_EAC,
+                7 => gl::COMPRESSED_SIGNED_R11_EAC,
+                8 => gl::COMPRESSED_RG11_EAC,
+                _ => gl::COMPRESSED_SIGNED_RG11_EAC,
+            })
+        }
+        // Unorm and sRGB variants alternate for each block size.
+        VK_FORMAT_ASTC_4X4_UNORM_BLOCK..=VK_FORMAT_ASTC_12X12_SRGB_BLOCK => {
+            let index = vk_format - VK_FORMAT_ASTC_4X4_UNORM_BLOCK;
+            let base = if index % 2 == 0 {
+                COMPRESSED_RGBA_ASTC_4X4_KHR
+            } else {
+                COMPRESSED_SRGB8_ALPHA8_ASTC_4X4_KHR
+            };
+            Some(base + index / 2)
+        }
+        _ => None,
+    }
+}
+
+// `OES_compressed_ETC1_RGB8_texture`.
+#[cfg(test)]
+pub(crate) const ETC1_RGB8_OES: GLenum = 0x8D64;
+
+// Builds a KTX 1 header of an 8x8 ETC1 image with the given level count.
+#[cfg(test)]
+pub(crate) fn ktx1_header(levels: u32) -> Vec<u8> {
+    let mut bytes = KTX1_IDENTIFIER.to_vec();
+    // Endianness, type, type size, format, internal format, base internal format,
+    // width, height, depth, array elements, faces, levels and key/value bytes.
+    let header = [0x04030201, 0, 1, 0, ETC1_RGB8_OES, gl::RGB, 8, 8, 0, 0, 1, levels, 0];
+    for value in header.iter() {
+        bytes.extend_from_slice(&[*value as u8, (*value >> 8) as u8,
+                                  (*value >> 16) as u8, (*value >> 24) as u8]);
+    }
+    bytes
+}
+
+#[test]
+fn test_parse_ktx1() {
+    let mut bytes = ktx1_header(2);
+    bytes.extend_from_slice(&[32, 0, 0, 0]);
+    bytes.extend_from_slice(&[1; 32]);
+    bytes.extend_from_slice(&[8, 0, 0, 0]);
+    bytes.extend_from_slice(&[2; 8]);
+
+    let image = parse(&bytes).unwrap();
+    assert_eq!(image.internal_format, ETC1_RGB8_OES);
+    assert_eq!((image.width, image.height), (8, 8));
+    assert_eq!(image.levels, vec![&[1u8; 32][..], &[2u8; 8][..]]);
+}
+
+#[test]
+fn test_vk_format_to_gl() {
+    assert_eq!(vk_format_to_gl(147), Some(gl::COMPRESSED_RGB8_ETC2));
+    assert_eq!(vk_format_to_gl(156), Some(gl::COMPRESSED_SIGNED_RG11_EAC));
+    assert_eq!(vk_format_to_gl(157), Some(0x93B0));
+    assert_eq!(vk_format_to_gl(184), Some(0x93DD));
+    assert_eq!(vk_format_to_gl(37), None);
+}
+
+#[test]
+fn test_parse_invalid_level_count() {
+    // More levels than the file can hold.
+    let bytes = ktx1_header(0xFFFF_FFFF);
+    assert!(parse(&bytes).is_err());
+
+    // More levels than an 8x8 image has, each of zero bytes.
+    let mut bytes = ktx1_header(32);
+    for _ in 0..32 {
+        bytes.extend_from_slice(&[0; 4]);
+    }
+    assert!(parse(&bytes).is_err());
+    let mut bytes = ktx1_header(4);
+    for _ in 0..4 {
+        bytes.extend_from_slice(&[0; 4]);
+    }
+    assert_eq!(parse(&bytes).unwrap().levels.len(), 4);
+}
@@ -9,10 +9,17 @@ extern crate image;
 extern crate graphics;
 extern crate rusttype;
 extern crate texture as texture_lib;
+#[cfg(feature = "gl-debug")]
+extern crate log;
 
 pub use shader_version::OpenGL;
 pub use crate::back_end::GlGraphics;
-pub use crate::texture::Texture;
+pub use crate::capabilities::Capabilities;
+pub use crate::color_space::ColorSpace;
+pub use crate::draw_state::{BlendEquation, BlendFactor, CustomBlend};
+pub use crate::texture::{premultiply_alpha, premultiply_alpha_linear, AlphaMode, Texture, TextureFormat, Wrap};
+pub use crate::render_target::RenderTarget;
+pub use crate::stats::FrameStats;
 pub use texture_lib::*;
 
 pub mod shader_utils;
@@ -21,7 +28,25 @@ pub mod error;
 
 #[allow(non_upper_case_globals, missing_docs)]
 pub mod gl;
+#[cfg(feature = "gl-debug")]
+pub mod debug;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "egl")]
+pub mod egl;
+
+// The bindings used by the crate, which check for errors with `gl-debug`.
+#[cfg(feature = "gl-debug")]
+mod checked_gl;
+#[cfg(not(feature = "gl-debug"))]
+use crate::gl as checked_gl;
 
 mod back_end;
+mod capabilities;
+mod color_space;
 mod texture;
+mod render_target;
+mod ktx;
+mod context;
+mod stats;
 mod draw_state;
@@ -0,0 +1,255 @@
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+
+use crate::checked_gl as gl;
+use crate::gl::types::{GLenum, GLint, GLsizei, GLuint};
+
+use crate::{CreateTexture, Format, Texture, TextureSettings};
+use crate::context;
+use crate::error::Error;
+
+/// Wraps an OpenGL framebuffer object to draw into.
+/// The framebuffer gets deleted when running out of scope,
+/// unless it was created outside of this crate.
+///
+/// Use `GlGraphics::draw_to` to draw into a render target.
+pub struct RenderTarget {
+    // Shared with the registry of render targets to recreate.
+    framebuffer: Rc<Framebuffer>,
+    flip_y: bool,
+    owned: bool,
+}
+
+// The objects of a render target, which are replaced after the context is lost.
+struct Framebuffer {
+    fbo: Cell<GLuint>,
+    stencil: Cell<Option<GLuint>>,
+    // The context generation the ids belong to.
+    generation: Cell<u32>,
+    texture: Option<Texture>,
+    width: u32,
+    height: u32,
+}
+
+thread_local! {
+    // The render targets created by this crate on the current thread.
+    static FRAMEBUFFERS: RefCell<Vec<Weak<Framebuffer>>> = RefCell::new(Vec::new());
+}
+
+/// Recreates the framebuffers of a lost context in the current one.
+///
+/// The textures must be recreated first, their contents are cleared.
+/// Framebuffers created elsewhere are left to their owner.
+pub(crate) fn recreate_render_targets() -> Result<(), Error> {
+    let framebuffers: Vec<Rc<Framebuffer>> = FRAMEBUFFERS.with(|framebuffers| {
+        let mut framebuffers = framebuffers.borrow_mut();
+        framebuffers.retain(|f| f.upgrade().is_some());
+        framebuffers.iter().filter_map(|f| f.upgrade()).collect()
+    });
+    for framebuffer in framebuffers {
+        let texture = match framebuffer.texture {
+            Some(ref texture) => texture,
+            None => continue,
+        };
+        let (fbo, stencil, status) = unsafe {
+            attach(texture.get_id(),
+                   framebuffer.width,
+                   framebuffer.height,
+                   framebuffer.stencil.get().is_some())
+        };
+        framebuffer.fbo.set(fbo);
+        framebuffer.stencil.set(stencil);
+        framebuffer.generation.set(context::generation());
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(Error::FramebufferIncomplete(status));
+        }
+    }
+    Ok(())
+}
+
+// Creates a framebuffer drawing into a texture, with an optional stencil buffer.
+// Returns the framebuffer, the stencil renderbuffer and the framebuffer status.
+unsafe fn attach(texture: GLuint,
+                 width: u32,
+                 height: u32,
+                 stencil: bool)
+                 -> (GLuint, Option<GLuint>, GLenum) {
+    let mut previous = 0;
+    gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut previous);
+
+    let mut fbo = 0;
+    gl::GenFramebuffers(1, &mut fbo);
+    gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+    gl::FramebufferTexture2D(gl::FRAMEBUFFER,
+                             gl::COLOR_ATTACHMENT0,
+                             gl::TEXTURE_2D,
+                             texture,
+                             0);
+    let mut renderbuffer = None;
+    if stencil {
+        let mut id = 0;
+        gl::GenRenderbuffers(1, &mut id);
+        gl::BindRenderbuffer(gl::RENDERBUFFER, id);
+        gl::RenderbufferStorage(gl::RENDERBUFFER,
+                                gl::STENCIL_INDEX8,
+                                width as GLint,
+                                height as GLint);
+        gl::FramebufferRenderbuffer(gl::FRAMEBUFFER,
+                                    gl::STENCIL_ATTACHMENT,
+                                    gl::RENDERBUFFER,
+                                    id);
+        gl::BindRenderbuffer(gl::RENDERBUFFER, 0);
+        renderbuffer = Some(id);
+    }
+    let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+
+    gl::BindFramebuffer(gl::FRAMEBUFFER, previous as GLuint);
+    (fbo, renderbuffer, status)
+}
+
+// Creates a cleared color texture for a render target.
+fn create_texture(width: u32, height: u32, settings: &TextureSettings) -> Result<Texture, Error> {
+    let memory = vec![0; (width * height * 4) as usize];
+    <Texture as CreateTexture<()>>::create(&mut (),
+                                           Format::Rgba8,
+                                           &memory,
+                                           [width, height],
+                                           settings)
+}
+
+impl RenderTarget {
+    /// Creates a render target with a color texture of the given size.
+    pub fn new(width: u32, height: u32, settings: &TextureSettings) -> Result<Self, Error> {
+        RenderTarget::create(width, height, settings, false)
+    }
+
+    /// Creates a render target with a color texture and a stencil buffer,
+    /// which is required for clipping.
+    pub fn with_stencil(width: u32,
+                        height: u32,
+                        settings: &TextureSettings)
+                        -> Result<Self, Error> {
+        RenderTarget::create(width, height, settings, true)
+    }
+
+    /// Wraps a framebuffer object created elsewhere, for example by a host application.
+    ///
+    /// The framebuffer is not deleted when the render target is dropped,
+    /// nor recreated after the context is lost.
+    /// Set `flip_y` when the contents will be used as a texture,
+    /// so the first row ends up at the top of the image.
+    pub fn from_raw(fbo: GLuint, width: u32, height: u32, flip_y: bool) -> Self {
+        RenderTarget {
+            framebuffer: Rc::new(Framebuffer {
+                fbo: Cell::new(fbo),
+                stencil: Cell::new(None),
+                generation: Cell::new(context::generation()),
+                texture: None,
+                width: width,
+                height: height,
+            }),
+            flip_y: flip_y,
+            owned: false,
+        }
+    }
+
+    fn create(width: u32,
+              height: u32,
+              settings: &TextureSettings,
+              stencil: bool)
+              -> Result<Self, Error> {
+        let mut texture = create_texture(width, height, settings)?;
+        // The contents are lost with the context, recreate it cleared
+        // instead of keeping a copy of the initial pixels.
+        let settings = *settings;
+        texture.set_reload(move || create_texture(width, height, &settings));
+        let (fbo, renderbuffer, status) = unsafe { attach(texture.get_id(), width, height, stencil) };
+
+        let framebuffer = Rc::new(Framebuffer {
+            fbo: Cell::new(fbo),
+            stencil: Cell::new(renderbuffer),
+            generation: Cell::new(context::generation()),
+            texture: Some(texture),
+            width: width,
+            height: height,
+        });
+        FRAMEBUFFERS.with(|framebuffers| {
+            let mut framebuffers = framebuffers.borrow_mut();
+            if framebuffers.len() == framebuffers.capacity() {
+                framebuffers.retain(|f| f.upgrade().is_some());
+            }
+            framebuffers.push(Rc::downgrade(&framebuffer));
+        });
+        let target = RenderTarget {
+            framebuffer: framebuffer,
+            flip_y: true,
+            owned: true,
+        };
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(Error::FramebufferIncomplete(status));
+        }
+        Ok(target)
+    }
+
+    /// Gets the OpenGL id of the framebuffer object.
+    #[inline(always)]
+    pub fn get_fbo(&self) -> GLuint {
+        self.framebuffer.fbo.get()
+    }
+
+    /// Gets the color texture, which can be drawn like any other texture.
+    ///
+    /// Returns `None` for framebuffers created elsewhere.
+    pub fn texture(&self) -> Option<&Texture> {
+        self.framebuffer.texture.as_ref()
+    }
+
+    /// Gets the size of the render target in pixels.
+    pub fn get_size(&self) -> (u32, u32) {
+        (self.framebuffer.width, self.framebuffer.height)
+    }
+
+    /// Returns `true` if drawing is flipped vertically to store the first row at the top.
+    pub fn flip_y(&self) -> bool {
+        self.flip_y
+    }
+}
+
+impl Drop for RenderTarget {
+    fn drop(&mut self) {
+        let framebuffer = &self.framebuffer;
+        if !context::is_current(framebuffer.generation.get()) {
+            return;
+        }
+        unsafe {
+            if let Some(id) = framebuffer.stencil.get() {
+                gl::DeleteRenderbuffers(1, &id);
+            }
+            if self.owned {
+                gl::DeleteFramebuffers(1, &framebuffer.fbo.get());
+            }
+        }
+    }
+}
+
+/// Reads RGBA pixels from the bound framebuffer.
+///
+/// The rows are returned bottom-up, in the order OpenGL stores them.
+pub(crate) fn read_rgba(x: i32, y: i32, width: u32, height: u32) -> Vec<u8> {
+    let mut pixels = vec![0u8; (width * height * 4) as usize];
+    unsafe {
+        // Rows are tightly packed, whatever the width.
+        let mut alignment = 0;
+        gl::GetIntegerv(gl::PACK_ALIGNMENT, &mut alignment);
+        gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+        gl::ReadPixels(x as GLint,
+                       y as GLint,
+                       width as GLsizei,
+                       height as GLsizei,
+                       gl::RGBA,
+                       gl::UNSIGNED_BYTE,
+                       pixels.as_mut_ptr() as *mut _);
+        gl::PixelStorei(gl::PACK_ALIGNMENT, alignment);
+    }
+    pixels
+}
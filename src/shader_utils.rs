@@ -1,10 +1,104 @@
 //! Helper functions for dealing with shaders.
 
 // External crates.
-use crate::gl;
-use crate::gl::types::{GLboolean, GLchar, GLenum, GLint, GLsizeiptr, GLuint};
+use crate::checked_gl as gl;
+use crate::gl::types::{GLboolean, GLchar, GLenum, GLint, GLsizei, GLsizeiptr, GLuint};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::CString;
 use std::{ptr, mem};
+use shader_version::OpenGL;
+use crate::capabilities::Capabilities;
+use crate::context;
+use crate::error::Error;
+
+/// The shading language versions of the built-in shaders.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShaderVariant {
+    /// GLSL ES 1.00, for OpenGL ES 2.0.
+    Es100,
+    /// GLSL ES 3.00, for OpenGL ES 3.0.
+    Es300,
+    /// GLSL 1.50, for desktop OpenGL 3.2.
+    Glsl150,
+    /// GLSL 3.30, for desktop OpenGL 3.3 and later.
+    Glsl330,
+}
+
+impl ShaderVariant {
+    /// Selects the newest variant allowed by `opengl` that the context can compile.
+    ///
+    /// On OpenGL ES, `OpenGL::V2_0` and `V2_1` select GLSL ES 1.00,
+    /// later versions allow GLSL ES 3.00.
+    /// On desktop OpenGL 3.2 and later, `OpenGL::V3_3` and later allow GLSL 3.30,
+    /// other versions select GLSL 1.50.
+    /// Older desktop contexts compile the ES variants they are compatible with.
+    /// Returns `Err` if the context compiles none of them.
+    pub fn select(opengl: OpenGL, capabilities: &Capabilities) -> Result<Self, Error> {
+        if !capabilities.es && capabilities.version >= [3, 2] {
+            let requested = match opengl {
+                OpenGL::V2_0 | OpenGL::V2_1 | OpenGL::V3_0 | OpenGL::V3_1 | OpenGL::V3_2 => {
+                    ShaderVariant::Glsl150
+                }
+                _ => ShaderVariant::Glsl330,
+            };
+            let supported = if capabilities.version >= [3, 3] {
+                ShaderVariant::Glsl330
+            } else {
+                ShaderVariant::Glsl150
+            };
+            return Ok(requested.min(supported));
+        }
+
+        let requested = match opengl {
+            OpenGL::V2_0 | OpenGL::V2_1 => ShaderVariant::Es100,
+            _ => ShaderVariant::Es300,
+        };
+        let supported = if capabilities.es {
+            if capabilities.version[0] >= 3 {
+                Some(ShaderVariant::Es300)
+            } else {
+                Some(ShaderVariant::Es100)
+            }
+        } else if capabilities.has_extension("GL_ARB_ES3_compatibility") ||
+                  capabilities.version >= [4, 3] {
+            Some(ShaderVariant::Es300)
+        } else if capabilities.has_extension("GL_ARB_ES2_compatibility") ||
+                  capabilities.version >= [4, 1] {
+            Some(ShaderVariant::Es100)
+        } else {
+            None
+        };
+        match supported {
+            Some(supported) => Ok(requested.min(supported)),
+            None => {
+                Err(Error::Unsupported(format!("No shader variant for {:?} compiles on \
+                                                \"{}\", which supports GLSL \"{}\"",
+                                               opengl,
+                                               capabilities.version_string,
+                                               capabilities.glsl_version_string)))
+            }
+        }
+    }
+
+    /// Gets the `#version` line of the variant.
+    pub fn version_directive(&self) -> &'static str {
+        match *self {
+            ShaderVariant::Es100 => "#version 100",
+            ShaderVariant::Es300 => "#version 300 es",
+            ShaderVariant::Glsl150 => "#version 150 core",
+            ShaderVariant::Glsl330 => "#version 330 core",
+        }
+    }
+
+    /// Returns `true` for the variants of OpenGL ES.
+    pub fn is_es(&self) -> bool {
+        match *self {
+            ShaderVariant::Es100 | ShaderVariant::Es300 => true,
+            ShaderVariant::Glsl150 | ShaderVariant::Glsl330 => false,
+        }
+    }
+}
 
 /// Describes a shader attribute.
 pub struct DynamicAttribute {
@@ -18,12 +112,17 @@ pub struct DynamicAttribute {
     normalize: GLboolean,
     /// The type, for example gl::FLOAT.
     ty: GLenum,
+    /// The context generation the buffer belongs to.
+    generation: u32,
 }
 
 impl Drop for DynamicAttribute {
     fn drop(&mut self) {
-        unsafe {
-            gl::DeleteBuffers(1, &self.vbo);
+        // The buffer of a lost context may share its id with one of the current context.
+        if context::is_current(self.generation) {
+            unsafe {
+                gl::DeleteBuffers(1, &self.vbo);
+            }
         }
     }
 }
@@ -33,9 +132,18 @@ impl DynamicAttribute {
     ///
     /// The vertex array object remembers the format for later.
     pub fn bind_vao(&self, vao: GLuint) {
-        let stride = 0;
         unsafe {
             gl::BindVertexArray(vao);
+        }
+        self.bind();
+    }
+
+    /// Binds the buffer as the source of the attribute.
+    ///
+    /// Without a vertex array object, this must be repeated before each draw.
+    pub fn bind(&self) {
+        let stride = 0;
+        unsafe {
             gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo);
             gl::VertexAttribPointer(self.location,
                                     self.size,
@@ -51,7 +159,7 @@ impl DynamicAttribute {
            size: i32,
            normalize: GLboolean,
            ty: GLenum)
-           -> Result<Self, String> {
+           -> Result<Self, Error> {
         let location = attribute_location(program, name)?;
         let mut vbo = 0;
         unsafe {
@@ -63,32 +171,33 @@ impl DynamicAttribute {
             location: location,
             normalize: normalize,
             ty: ty,
+            generation: context::generation(),
         };
         Ok(res)
     }
 
     /// Create XYZ vertex attribute.
-    pub fn xyz(program: GLuint, name: &str) -> Result<DynamicAttribute, String> {
+    pub fn xyz(program: GLuint, name: &str) -> Result<DynamicAttribute, Error> {
         DynamicAttribute::new(program, name, 3, gl::FALSE, gl::FLOAT)
     }
 
     /// Create XY vertex attribute.
-    pub fn xy(program: GLuint, name: &str) -> Result<DynamicAttribute, String> {
+    pub fn xy(program: GLuint, name: &str) -> Result<DynamicAttribute, Error> {
         DynamicAttribute::new(program, name, 2, gl::FALSE, gl::FLOAT)
     }
 
     /// Create RGB color attribute.
-    pub fn rgb(program: GLuint, name: &str) -> Result<DynamicAttribute, String> {
+    pub fn rgb(program: GLuint, name: &str) -> Result<DynamicAttribute, Error> {
         DynamicAttribute::new(program, name, 3, gl::FALSE, gl::FLOAT)
     }
 
     /// Create RGBA color attribute.
-    pub fn rgba(program: GLuint, name: &str) -> Result<DynamicAttribute, String> {
+    pub fn rgba(program: GLuint, name: &str) -> Result<DynamicAttribute, Error> {
         DynamicAttribute::new(program, name, 4, gl::FALSE, gl::FLOAT)
     }
 
     /// Create texture coordinate attribute.
-    pub fn uv(program: GLuint, name: &str) -> Result<DynamicAttribute, String> {
+    pub fn uv(program: GLuint, name: &str) -> Result<DynamicAttribute, Error> {
         DynamicAttribute::new(program, name, 2, gl::FALSE, gl::FLOAT)
     }
 
@@ -101,17 +210,23 @@ impl DynamicAttribute {
                        mem::transmute(data.as_ptr()),
                        gl::DYNAMIC_DRAW);
     }
+
+    /// Disables the attribute array, so draws without a vertex array object
+    /// do not read from it.
+    pub unsafe fn disable(&self) {
+        gl::DisableVertexAttribArray(self.location);
+    }
 }
 
 /// Compiles a shader.
 ///
-/// Returns a shader or a message with the error.
-pub fn compile_shader(shader_type: GLenum, source: &str) -> Result<GLuint, String> {
+/// Returns a shader or an error with the info log.
+pub fn compile_shader(shader_type: GLenum, source: &str) -> Result<GLuint, Error> {
     unsafe {
         let shader = gl::CreateShader(shader_type);
         let c_source = match CString::new(source) {
             Ok(x) => x,
-            Err(err) => return Err(format!("compile_shader: {}", err)),
+            Err(err) => return Err(Error::ShaderCompile(format!("compile_shader: {}", err))),
         };
         gl::ShaderSource(shader, 1, &c_source.as_ptr(), ptr::null());
         drop(source);
@@ -125,10 +240,10 @@ pub fn compile_shader(shader_type: GLenum, source: &str) -> Result<GLuint, Strin
             gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
 
             if len == 0 {
-                Err("Compilation failed with no log. \
+                Err(Error::ShaderCompile("Compilation failed with no log. \
                      The OpenGL context might have been created on another thread, \
                      or not have been created."
-                    .to_string())
+                    .to_string()))
             } else {
                 // Subtract 1 to skip the trailing null character.
                 let mut buf = vec![0; len as usize - 1];
@@ -139,25 +254,60 @@ pub fn compile_shader(shader_type: GLenum, source: &str) -> Result<GLuint, Strin
 
                 gl::DeleteShader(shader);
 
-                Err(String::from_utf8(buf).ok().expect("ShaderInfoLog not valid utf8"))
+                Err(Error::ShaderCompile(String::from_utf8_lossy(&buf).into_owned()))
             }
         }
     }
 }
 
+/// Links a program from a compiled vertex and fragment shader.
+///
+/// Returns the program or an error with the info log.
+pub fn link_program(vertex_shader: GLuint, fragment_shader: GLuint) -> Result<GLuint, Error> {
+    unsafe {
+        let program = gl::CreateProgram();
+        gl::AttachShader(program, vertex_shader);
+        gl::AttachShader(program, fragment_shader);
+        gl::LinkProgram(program);
+        let mut status = gl::FALSE as GLint;
+        gl::GetProgramiv(program, gl::LINK_STATUS, &mut status);
+        if status == (gl::TRUE as GLint) {
+            Ok(program)
+        } else {
+            let mut len = 0;
+            gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
+
+            let log = if len == 0 {
+                "Linking failed with no log.".to_string()
+            } else {
+                // Subtract 1 to skip the trailing null character.
+                let mut buf = vec![0; len as usize - 1];
+                gl::GetProgramInfoLog(program,
+                                      len,
+                                      ptr::null_mut(),
+                                      buf.as_mut_ptr() as *mut GLchar);
+                String::from_utf8_lossy(&buf).into_owned()
+            };
+            gl::DeleteProgram(program);
+
+            Err(Error::ProgramLink(log))
+        }
+    }
+}
+
 /// Finds attribute location from a program.
 ///
 /// Returns `Err` if there is no attribute with such name.
-pub fn attribute_location(program: GLuint, name: &str) -> Result<GLuint, String> {
+pub fn attribute_location(program: GLuint, name: &str) -> Result<GLuint, Error> {
     unsafe {
         let c_name = match CString::new(name) {
             Ok(x) => x,
-            Err(err) => return Err(format!("attribute_location: {}", err)),
+            Err(_) => return Err(Error::MissingAttribute(name.to_string())),
         };
         let id = gl::GetAttribLocation(program, c_name.as_ptr());
         drop(c_name);
         if id < 0 {
-            Err(format!("Attribute '{}' does not exists in shader", name))
+            Err(Error::MissingAttribute(name.to_string()))
         } else {
             Ok(id as GLuint)
         }
@@ -167,18 +317,284 @@ pub fn attribute_location(program: GLuint, name: &str) -> Result<GLuint, String>
 /// Finds uniform location from a program.
 ///
 /// Returns `Err` if there is no uniform with such name.
-pub fn uniform_location(program: GLuint, name: &str) -> Result<GLuint, String> {
+/// The location is converted to `GLuint`, while the `glUniform*` functions take `GLint`;
+/// `ShaderProgram::uniform_location` returns it unchanged.
+pub fn uniform_location(program: GLuint, name: &str) -> Result<GLuint, Error> {
     unsafe {
         let c_name = match CString::new(name) {
             Ok(x) => x,
-            Err(err) => return Err(format!("uniform_location: {}", err)),
+            Err(_) => return Err(Error::MissingUniform(name.to_string())),
         };
         let id = gl::GetUniformLocation(program, c_name.as_ptr());
         drop(c_name);
         if id < 0 {
-            Err(format!("Uniform '{}' does not exists in shader", name))
+            Err(Error::MissingUniform(name.to_string()))
         } else {
             Ok(id as GLuint)
         }
     }
 }
+
+/// A uniform declared by a linked program, as reported by `glGetActiveUniform`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ActiveUniform {
+    /// The name, with `[0]` appended for arrays by some drivers.
+    pub name: String,
+    /// The type, such as `gl::FLOAT_VEC4` or `gl::SAMPLER_2D`.
+    pub ty: GLenum,
+    /// The number of elements, 1 unless the uniform is an array.
+    pub size: i32,
+}
+
+/// A linked shader program, owning its shaders.
+///
+/// Attribute and uniform locations are looked up once per name and cached.
+/// The uniform setters change the program in use, which must be this one:
+/// call `gl::UseProgram` with `id()` first.
+pub struct ShaderProgram {
+    vertex_shader: GLuint,
+    fragment_shader: GLuint,
+    program: GLuint,
+    attributes: RefCell<HashMap<String, GLuint>>,
+    uniforms: RefCell<HashMap<String, GLint>>,
+    // The context generation the objects belong to.
+    generation: u32,
+}
+
+impl Drop for ShaderProgram {
+    fn drop(&mut self) {
+        if !context::is_current(self.generation) {
+            return;
+        }
+        unsafe {
+            gl::DeleteProgram(self.program);
+            gl::DeleteShader(self.vertex_shader);
+            gl::DeleteShader(self.fragment_shader);
+        }
+    }
+}
+
+impl ShaderProgram {
+    /// Compiles the shaders and links them.
+    ///
+    /// Returns an error with the info log if compiling or linking fails.
+    pub fn new(vertex_source: &str, fragment_source: &str) -> Result<Self, Error> {
+        let vertex_shader = compile_shader(gl::VERTEX_SHADER, vertex_source)?;
+        let fragment_shader = match compile_shader(gl::FRAGMENT_SHADER, fragment_source) {
+            Ok(id) => id,
+            Err(e) => {
+                unsafe {
+                    gl::DeleteShader(vertex_shader);
+                }
+                return Err(e);
+            }
+        };
+        let program = match link_program(vertex_shader, fragment_shader) {
+            Ok(program) => program,
+            Err(e) => {
+                unsafe {
+                    gl::DeleteShader(vertex_shader);
+                    gl::DeleteShader(fragment_shader);
+                }
+                return Err(e);
+            }
+        };
+        Ok(ShaderProgram {
+            vertex_shader: vertex_shader,
+            fragment_shader: fragment_shader,
+            program: program,
+            attributes: RefCell::new(HashMap::new()),
+            uniforms: RefCell::new(HashMap::new()),
+            generation: context::generation(),
+        })
+    }
+
+    /// Gets the OpenGL id of the program.
+    pub fn id(&self) -> GLuint {
+        self.program
+    }
+
+    /// Finds the location of an attribute.
+    ///
+    /// Returns `Err` if there is no active attribute with such name.
+    pub fn attribute_location(&self, name: &str) -> Result<GLuint, Error> {
+        if let Some(&location) = self.attributes.borrow().get(name) {
+            return Ok(location);
+        }
+        let location = attribute_location(self.program, name)?;
+        self.attributes.borrow_mut().insert(name.to_string(), location);
+        Ok(location)
+    }
+
+    /// Finds the location of a uniform.
+    ///
+    /// Returns `Err` if there is no active uniform with such name,
+    /// which includes uniforms the shader compiler removed as unused.
+    pub fn uniform_location(&self, name: &str) -> Result<GLint, Error> {
+        if let Some(&location) = self.uniforms.borrow().get(name) {
+            return Ok(location);
+        }
+        let c_name = match CString::new(name) {
+            Ok(x) => x,
+            Err(_) => return Err(Error::MissingUniform(name.to_string())),
+        };
+        let location = unsafe { gl::GetUniformLocation(self.program, c_name.as_ptr()) };
+        if location < 0 {
+            return Err(Error::MissingUniform(name.to_string()));
+        }
+        self.uniforms.borrow_mut().insert(name.to_string(), location);
+        Ok(location)
+    }
+
+    /// Sets a `float` uniform.
+    pub fn set_f32(&self, name: &str, value: f32) -> Result<(), Error> {
+        let location = self.uniform_location(name)?;
+        unsafe {
+            gl::Uniform1f(location, value);
+        }
+        Ok(())
+    }
+
+    /// Sets a `vec2` uniform.
+    pub fn set_vec2(&self, name: &str, value: [f32; 2]) -> Result<(), Error> {
+        let location = self.uniform_location(name)?;
+        unsafe {
+            gl::Uniform2f(location, value[0], value[1]);
+        }
+        Ok(())
+    }
+
+    /// Sets a `vec3` uniform.
+    pub fn set_vec3(&self, name: &str, value: [f32; 3]) -> Result<(), Error> {
+        let location = self.uniform_location(name)?;
+        unsafe {
+            gl::Uniform3f(location, value[0], value[1], value[2]);
+        }
+        Ok(())
+    }
+
+    /// Sets a `vec4` uniform.
+    pub fn set_vec4(&self, name: &str, value: [f32; 4]) -> Result<(), Error> {
+        let location = self.uniform_location(name)?;
+        unsafe {
+            gl::Uniform4f(location, value[0], value[1], value[2], value[3]);
+        }
+        Ok(())
+    }
+
+    /// Sets a `mat3` uniform from its columns.
+    pub fn set_mat3(&self, name: &str, value: [[f32; 3]; 3]) -> Result<(), Error> {
+        let location = self.uniform_location(name)?;
+        unsafe {
+            // OpenGL ES 2.0 does not transpose.
+            gl::UniformMatrix3fv(location, 1, gl::FALSE, value.as_ptr() as *const f32);
+        }
+        Ok(())
+    }
+
+    /// Sets a `mat4` uniform from its columns.
+    pub fn set_mat4(&self, name: &str, value: [[f32; 4]; 4]) -> Result<(), Error> {
+        let location = self.uniform_location(name)?;
+        unsafe {
+            gl::UniformMatrix4fv(location, 1, gl::FALSE, value.as_ptr() as *const f32);
+        }
+        Ok(())
+    }
+
+    /// Sets a sampler uniform to read from a texture unit, 0 for `gl::TEXTURE0`.
+    pub fn set_sampler(&self, name: &str, unit: u32) -> Result<(), Error> {
+        let location = self.uniform_location(name)?;
+        unsafe {
+            gl::Uniform1i(location, unit as GLint);
+        }
+        Ok(())
+    }
+
+    /// Lists the uniforms the linked program uses.
+    pub fn active_uniforms(&self) -> Vec<ActiveUniform> {
+        let mut count = 0;
+        let mut max_length = 0;
+        unsafe {
+            gl::GetProgramiv(self.program, gl::ACTIVE_UNIFORMS, &mut count);
+            gl::GetProgramiv(self.program, gl::ACTIVE_UNIFORM_MAX_LENGTH, &mut max_length);
+        }
+        (0..count.max(0) as GLuint)
+            .map(|index| {
+                // The maximum length includes the trailing null character.
+                let mut buf = vec![0u8; max_length.max(1) as usize];
+                let mut length: GLsizei = 0;
+                let mut size: GLint = 0;
+                let mut ty: GLenum = 0;
+                unsafe {
+                    gl::GetActiveUniform(self.program,
+                                         index,
+                                         buf.len() as GLsizei,
+                                         &mut length,
+                                         &mut size,
+                                         &mut ty,
+                                         buf.as_mut_ptr() as *mut GLchar);
+                }
+                buf.truncate(length.max(0) as usize);
+                ActiveUniform {
+                    name: String::from_utf8_lossy(&buf).into_owned(),
+                    ty: ty,
+                    size: size,
+                }
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_select_shader_variant() {
+    let es = |major| {
+        Capabilities {
+            es: true,
+            version: [major, 0],
+            ..Capabilities::default()
+        }
+    };
+    let select = |opengl, capabilities: &Capabilities| {
+        ShaderVariant::select(opengl, capabilities).ok()
+    };
+    assert_eq!(select(OpenGL::V3_2, &es(3)), Some(ShaderVariant::Es300));
+    assert_eq!(select(OpenGL::V2_1, &es(3)), Some(ShaderVariant::Es100));
+    assert_eq!(select(OpenGL::V3_2, &es(2)), Some(ShaderVariant::Es100));
+
+    let desktop = |minor| {
+        Capabilities {
+            version: [3, minor],
+            ..Capabilities::default()
+        }
+    };
+    assert_eq!(select(OpenGL::V3_3, &desktop(3)), Some(ShaderVariant::Glsl330));
+    assert_eq!(select(OpenGL::V3_2, &desktop(3)), Some(ShaderVariant::Glsl150));
+    assert_eq!(select(OpenGL::V4_5, &desktop(2)), Some(ShaderVariant::Glsl150));
+    assert_eq!(select(OpenGL::V3_2, &desktop(1)), None);
+}
+
+#[test]
+fn test_shader_program_caches_locations() {
+    use crate::testing::{Arg, MockGl};
+
+    let mock = MockGl::new();
+    let program = ShaderProgram::new("void main() {}", "void main() {}").unwrap();
+    program.set_vec4("color", [1.0, 0.5, 0.25, 1.0]).unwrap();
+    program.set_f32("alpha", 0.5).unwrap();
+    program.set_vec4("color", [0.0; 4]).unwrap();
+    assert_eq!(program.attribute_location("pos").unwrap(),
+               program.attribute_location("pos").unwrap());
+
+    assert_eq!(mock.count("glGetUniformLocation"), 2);
+    assert_eq!(mock.count("glGetAttribLocation"), 1);
+    let color = program.uniform_location("color").unwrap();
+    let calls = mock.calls_to("glUniform4f");
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0].args[0], Arg::Int(color as i64));
+    assert_eq!(calls[0].args[2], Arg::Float(0.5));
+    assert!(program.active_uniforms().is_empty());
+
+    drop(program);
+    assert_eq!(mock.count("glDeleteProgram"), 1);
+    assert_eq!(mock.count("glDeleteShader"), 2);
+}
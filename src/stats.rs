@@ -0,0 +1,209 @@
+//! Frame statistics
+
+use crate::checked_gl as gl;
+use std::mem;
+use std::os::raw::c_void;
+
+use crate::gl::types::{GLenum, GLint, GLsizei, GLuint, GLuint64};
+use crate::context;
+use crate::error::Error;
+use crate::capabilities;
+
+// `EXT_disjoint_timer_query`.
+const TIME_ELAPSED_EXT: GLenum = 0x88BF;
+const GPU_DISJOINT_EXT: GLenum = 0x8FBB;
+
+// Queries in flight, results are read a few frames later to avoid stalling.
+const QUERIES: usize = 3;
+
+/// Counters of the work submitted by `GlGraphics` during a frame.
+///
+/// The counters are reset at the start of each `GlGraphics::draw`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// The number of draw calls.
+    pub draw_calls: u32,
+    /// The number of vertices drawn.
+    pub vertices: u32,
+    /// The number of bytes uploaded to vertex buffers.
+    pub bytes_uploaded: usize,
+    /// The number of times the shader program was switched.
+    pub program_switches: u32,
+    /// The number of times a texture was bound for drawing.
+    pub texture_binds: u32,
+    /// The number of times the draw state was changed.
+    pub draw_state_changes: u32,
+    /// The number of flushes because the vertex buffers were full.
+    pub overflow_flushes: u32,
+    /// The number of flushes because the next triangles needed a different
+    /// program, draw state, texture or color.
+    pub state_change_flushes: u32,
+    /// The GPU time of a recent frame in nanoseconds, if GPU timing is enabled.
+    ///
+    /// The time is only known a few frames later, so it lags behind the other counters.
+    pub gpu_time_ns: Option<u64>,
+}
+
+type GenQueries = extern "system" fn(GLsizei, *mut GLuint);
+type DeleteQueries = extern "system" fn(GLsizei, *const GLuint);
+type BeginQuery = extern "system" fn(GLenum, GLuint);
+type EndQuery = extern "system" fn(GLenum);
+type GetQueryObjectuiv = extern "system" fn(GLuint, GLenum, *mut GLuint);
+type GetQueryObjectui64v = extern "system" fn(GLuint, GLenum, *mut GLuint64);
+
+/// The query functions, which OpenGL ES 2.0 only has with the `EXT` suffix.
+#[derive(Clone, Copy)]
+pub(crate) struct QueryFunctions {
+    gen_queries: GenQueries,
+    delete_queries: DeleteQueries,
+    begin_query: BeginQuery,
+    end_query: EndQuery,
+    get_query_objectuiv: GetQueryObjectuiv,
+    // Results are 64 bit, but only OpenGL 3.3 and the extension return all of them.
+    get_query_objectui64v: Option<GetQueryObjectui64v>,
+}
+
+impl QueryFunctions {
+    /// Loads the functions of `EXT_disjoint_timer_query`, falling back to the core names.
+    ///
+    /// Returns `Err` if the context lacks the extension or any function.
+    pub fn load<F>(mut load: F) -> Result<Self, Error>
+        where F: FnMut(&str) -> *const c_void
+    {
+        if !capabilities::current().has_extension("GL_EXT_disjoint_timer_query") {
+            return Err(Error::Unsupported("GPU timing requires EXT_disjoint_timer_query"
+                .to_string()));
+        }
+        let mut get = |name: &str| {
+            let ptr = load(&format!("{}EXT", name));
+            if ptr.is_null() { load(name) } else { ptr }
+        };
+        let ptrs = [get("glGenQueries"),
+                    get("glDeleteQueries"),
+                    get("glBeginQuery"),
+                    get("glEndQuery"),
+                    get("glGetQueryObjectuiv")];
+        if ptrs.iter().any(|ptr| ptr.is_null()) {
+            return Err(Error::Unsupported("The query functions of EXT_disjoint_timer_query \
+                                           are not loaded"
+                .to_string()));
+        }
+        let ui64v = get("glGetQueryObjectui64v");
+        unsafe {
+            Ok(QueryFunctions {
+                gen_queries: mem::transmute(ptrs[0]),
+                delete_queries: mem::transmute(ptrs[1]),
+                begin_query: mem::transmute(ptrs[2]),
+                end_query: mem::transmute(ptrs[3]),
+                get_query_objectuiv: mem::transmute(ptrs[4]),
+                get_query_objectui64v: if ui64v.is_null() {
+                    None
+                } else {
+                    Some(mem::transmute(ui64v))
+                },
+            })
+        }
+    }
+}
+
+/// Measures the GPU time of frames with `EXT_disjoint_timer_query`.
+pub(crate) struct GpuTimer {
+    functions: QueryFunctions,
+    queries: [GLuint; QUERIES],
+    in_flight: [bool; QUERIES],
+    // The query to start next, the oldest in flight.
+    next: usize,
+    // Whether a query was started for the current frame.
+    running: bool,
+    last_time: Option<u64>,
+    // The context generation the queries belong to.
+    generation: u32,
+}
+
+impl GpuTimer {
+    /// Creates a timer with the query functions of the current context.
+    pub fn new(functions: QueryFunctions) -> Self {
+        let mut queries = [0; QUERIES];
+        (functions.gen_queries)(QUERIES as GLsizei, queries.as_mut_ptr());
+        GpuTimer {
+            functions: functions,
+            queries: queries,
+            in_flight: [false; QUERIES],
+            next: 0,
+            running: false,
+            last_time: None,
+            generation: context::generation(),
+        }
+    }
+
+    /// Gets the query functions, to create a timer again after the context was lost.
+    pub fn functions(&self) -> QueryFunctions {
+        self.functions
+    }
+
+    /// Reads finished queries and starts timing a frame.
+    pub fn begin(&mut self) -> Option<u64> {
+        let f = self.functions;
+        // Results arrive in order, starting with the oldest query.
+        for i in 0..QUERIES {
+            let index = (self.next + i) % QUERIES;
+            if !self.in_flight[index] {
+                continue;
+            }
+            let mut available = 0;
+            (f.get_query_objectuiv)(self.queries[index],
+                                    gl::QUERY_RESULT_AVAILABLE,
+                                    &mut available);
+            if available == 0 {
+                break;
+            }
+            let time = match f.get_query_objectui64v {
+                Some(get_query_objectui64v) => {
+                    let mut time: GLuint64 = 0;
+                    get_query_objectui64v(self.queries[index], gl::QUERY_RESULT, &mut time);
+                    time
+                }
+                None => {
+                    let mut time: GLuint = 0;
+                    (f.get_query_objectuiv)(self.queries[index], gl::QUERY_RESULT, &mut time);
+                    time as u64
+                }
+            };
+            self.in_flight[index] = false;
+            self.last_time = Some(time);
+        }
+        // Timings are meaningless when the GPU was interrupted.
+        // Reading the flag clears it for the following queries.
+        let mut disjoint: GLint = 0;
+        unsafe {
+            gl::GetIntegerv(GPU_DISJOINT_EXT, &mut disjoint);
+        }
+        if disjoint != 0 {
+            self.last_time = None;
+        }
+
+        self.running = !self.in_flight[self.next];
+        if self.running {
+            (f.begin_query)(TIME_ELAPSED_EXT, self.queries[self.next]);
+        }
+        self.last_time
+    }
+
+    /// Stops timing the frame.
+    pub fn end(&mut self) {
+        if self.running {
+            (self.functions.end_query)(TIME_ELAPSED_EXT);
+            self.in_flight[self.next] = true;
+            self.next = (self.next + 1) % QUERIES;
+            self.running = false;
+        }
+    }
+}
+
+impl Drop for GpuTimer {
+    fn drop(&mut self) {
+        if context::is_current(self.generation) {
+            (self.functions.delete_queries)(QUERIES as GLsizei, self.queries.as_ptr());
+        }
+    }
+}
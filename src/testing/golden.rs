@@ -0,0 +1,226 @@
+//! Comparison of rendered frames against stored golden images.
+//!
+//! Goldens are PNG files in `tests/golden` of the package being tested,
+//! or the directory in the `GOLDEN_DIR` environment variable.
+//! Set `UPDATE_GOLDENS=1` to write the rendered frames as the new goldens.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use image::{self, Rgba, RgbaImage};
+
+use crate::gl;
+use crate::{GlGraphics, RenderTarget};
+
+/// How much a rendered frame may differ from its golden image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tolerance {
+    /// The largest difference of a color channel for pixels that match.
+    pub channel: u8,
+    /// The percentage of pixels, from 0 to 100, that may exceed the channel difference.
+    pub pixels: f64,
+}
+
+impl Tolerance {
+    /// Requires every pixel to match exactly.
+    pub fn exact() -> Tolerance {
+        Tolerance {
+            channel: 0,
+            pixels: 0.0,
+        }
+    }
+}
+
+/// The result of comparing two images of the same size.
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    /// The number of pixels differing by more than the channel tolerance.
+    pub differing_pixels: usize,
+    /// The largest difference of a color channel.
+    pub max_difference: u8,
+    /// Marks the differing pixels in red over a faded copy of the actual image.
+    pub diff: RgbaImage,
+    /// Whether the differences are within the tolerance.
+    pub matches: bool,
+}
+
+/// Compares `actual` against `expected`, which must have the same size.
+pub fn compare_images(expected: &RgbaImage,
+                      actual: &RgbaImage,
+                      tolerance: Tolerance)
+                      -> Comparison {
+    assert_eq!(expected.dimensions(), actual.dimensions(), "Images differ in size");
+    let (width, height) = actual.dimensions();
+    let mut diff = RgbaImage::new(width, height);
+    let mut differing_pixels = 0;
+    let mut max_difference = 0;
+    for (x, y, pixel) in actual.enumerate_pixels() {
+        let a = pixel.data;
+        let e = expected.get_pixel(x, y).data;
+        let difference = (0..4)
+            .map(|i| (a[i] as i16 - e[i] as i16).abs() as u8)
+            .max()
+            .unwrap_or(0);
+        max_difference = max_difference.max(difference);
+        let marked = if difference > tolerance.channel {
+            differing_pixels += 1;
+            [255, 0, 0, 255]
+        } else {
+            let grey = ((a[0] as u32 + a[1] as u32 + a[2] as u32) / 3 / 4 + 192) as u8;
+            [grey, grey, grey, 255]
+        };
+        diff.put_pixel(x, y, Rgba { data: marked });
+    }
+    let total = (width as usize * height as usize).max(1);
+    let percentage = differing_pixels as f64 * 100.0 / total as f64;
+    Comparison {
+        differing_pixels: differing_pixels,
+        max_difference: max_difference,
+        diff: diff,
+        matches: percentage <= tolerance.pixels,
+    }
+}
+
+fn golden_dir() -> PathBuf {
+    if let Ok(dir) = env::var("GOLDEN_DIR") {
+        return PathBuf::from(dir);
+    }
+    let root = env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(root).join("tests").join("golden")
+}
+
+/// Reads back the viewport of the bound framebuffer and compares it
+/// against the golden image `name`.
+///
+/// On failure, the rendered frame and a diff image are written next to the golden
+/// as `<name>.actual.png` and `<name>.diff.png`.
+///
+/// # Panics
+/// If the frame does not match, or there is no golden and `UPDATE_GOLDENS` is not set.
+pub fn assert_matches_golden(graphics: &mut GlGraphics, name: &str, tolerance: Tolerance) {
+    let mut viewport = [0; 4];
+    unsafe {
+        gl::GetIntegerv(gl::VIEWPORT, viewport.as_mut_ptr());
+    }
+    let actual = graphics.read_pixels([viewport[0] as u32,
+                                       viewport[1] as u32,
+                                       viewport[2] as u32,
+                                       viewport[3] as u32]);
+    assert_image_matches_golden(&actual, name, tolerance);
+}
+
+/// Reads back a render target and compares it against the golden image `name`,
+/// such as for headless rendering with `draw_to`.
+///
+/// # Panics
+/// As `assert_matches_golden`.
+pub fn assert_target_matches_golden(graphics: &mut GlGraphics,
+                                    target: &RenderTarget,
+                                    name: &str,
+                                    tolerance: Tolerance) {
+    let actual = graphics.read_target(target);
+    assert_image_matches_golden(&actual, name, tolerance);
+}
+
+/// Compares an image, such as from `Texture::to_image`, against the golden image `name`.
+///
+/// # Panics
+/// As `assert_matches_golden`.
+pub fn assert_image_matches_golden(actual: &RgbaImage, name: &str, tolerance: Tolerance) {
+    let update = env::var("UPDATE_GOLDENS").map(|v| !v.is_empty() && v != "0").unwrap_or(false);
+    check_golden(&golden_dir(), update, actual, name, tolerance);
+}
+
+fn check_golden(dir: &Path, update: bool, actual: &RgbaImage, name: &str, tolerance: Tolerance) {
+    let path = dir.join(format!("{}.png", name));
+    if update {
+        ::std::fs::create_dir_all(dir).expect("Could not create the golden directory");
+        actual.save(&path).expect("Could not write the golden image");
+        return;
+    }
+
+    let expected = match image::open(&path) {
+        Ok(image) => image.to_rgba(),
+        Err(e) => {
+            panic!("Could not read golden {}: {}, run with UPDATE_GOLDENS=1 to create it",
+                   path.display(),
+                   e)
+        }
+    };
+    if expected.dimensions() != actual.dimensions() {
+        panic!("Golden {} is {:?}, the frame is {:?}",
+               name,
+               expected.dimensions(),
+               actual.dimensions());
+    }
+    let comparison = compare_images(&expected, actual, tolerance);
+    if !comparison.matches {
+        let actual_path = dir.join(format!("{}.actual.png", name));
+        let diff_path = dir.join(format!("{}.diff.png", name));
+        let _ = actual.save(&actual_path);
+        let _ = comparison.diff.save(&diff_path);
+        panic!("Frame differs from golden {}: {} pixels differ, by up to {}, see {}",
+               name,
+               comparison.differing_pixels,
+               comparison.max_difference,
+               diff_path.display());
+    }
+}
+
+#[test]
+fn test_compare_images() {
+    let expected = RgbaImage::from_raw(2, 2, vec![0; 16]).unwrap();
+    let mut actual = expected.clone();
+    actual.put_pixel(1, 1, Rgba { data: [10, 0, 0, 0] });
+
+    let exact = compare_images(&expected, &actual, Tolerance::exact());
+    assert!(!exact.matches);
+    assert_eq!(exact.differing_pixels, 1);
+    assert_eq!(exact.max_difference, 10);
+    assert_eq!(exact.diff.get_pixel(1, 1).data, [255, 0, 0, 255]);
+
+    let channel = Tolerance { channel: 10, pixels: 0.0 };
+    assert!(compare_images(&expected, &actual, channel).matches);
+    let pixels = Tolerance { channel: 0, pixels: 25.0 };
+    assert!(compare_images(&expected, &actual, pixels).matches);
+}
+
+#[test]
+fn test_check_golden() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let dir = env::temp_dir().join(format!("opengles_graphics_golden_{}", ::std::process::id()));
+    let black = RgbaImage::from_raw(2, 2, vec![0; 16]).unwrap();
+    let mut white = black.clone();
+    white.put_pixel(0, 0, Rgba { data: [255; 4] });
+    let check = |image: &RgbaImage, update| {
+        catch_unwind(AssertUnwindSafe(|| {
+            check_golden(&dir, update, image, "square", Tolerance::exact())
+        }))
+    };
+
+    assert!(check(&black, false).is_err());
+    assert!(check(&black, true).is_ok());
+    assert!(check(&black, false).is_ok());
+    assert!(check(&white, false).is_err());
+    assert!(dir.join("square.actual.png").exists());
+    assert!(dir.join("square.diff.png").exists());
+    let _ = ::std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_read_target() {
+    use crate::testing::MockGl;
+    use crate::{OpenGL, TextureSettings};
+
+    let mock = MockGl::new();
+    let mut g = GlGraphics::new(OpenGL::V2_1);
+    let target = RenderTarget::new(3, 2, &TextureSettings::new()).unwrap();
+    mock.clear();
+    let image = g.read_target(&target);
+
+    assert_eq!(image.dimensions(), (3, 2));
+    let binds: Vec<i64> = mock.calls_to("glBindFramebuffer").iter().map(|c| c.int(1)).collect();
+    assert_eq!(binds, [target.get_fbo() as i64, 0]);
+    assert_eq!(mock.count("glReadPixels"), 1);
+}
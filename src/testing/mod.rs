@@ -0,0 +1,601 @@
+//! A fake OpenGL implementation for testing without a GPU.
+//!
+//! `MockGl::new` loads function pointers that record every call instead of rendering,
+//! and simulate just enough state for this crate to run:
+//! object ids are counted up, shaders always compile and link,
+//! framebuffers are complete and `glGetIntegerv` returns the values set
+//! with `glPixelStorei`, `glBindFramebuffer`, `glViewport` or `MockGl::set_integer`.
+//! `glGetError` returns no error, unless one is raised with `MockGl::set_error`.
+//!
+//! ```ignore
+//! let mock = MockGl::new();
+//! let mut g = GlGraphics::new(OpenGL::V2_1);
+//! // ... draw ...
+//! assert_eq!(mock.count("glDrawArrays"), 1);
+//! ```
+//!
+//! For rendering tests on a real context, `assert_matches_golden` compares
+//! the framebuffer against a stored image, and `assert_target_matches_golden`
+//! a render target, as drawn to headlessly.
+
+#![allow(non_snake_case)]
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_void;
+use std::ptr;
+use std::slice;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::gl;
+use crate::gl::types::{GLchar, GLenum, GLint, GLintptr, GLsizei, GLsizeiptr, GLubyte, GLuint};
+
+mod stubs;
+mod golden;
+
+pub use self::golden::{assert_image_matches_golden, assert_matches_golden,
+                       assert_target_matches_golden, compare_images, Comparison, Tolerance};
+
+/// An argument of a recorded call.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arg {
+    /// An integer, enum, boolean or size.
+    Int(i64),
+    /// A floating point number.
+    Float(f64),
+    /// The address of a pointer.
+    Ptr(usize),
+}
+
+/// A recorded call to an OpenGL function.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    /// The name of the function, such as `glDrawArrays`.
+    pub name: &'static str,
+    /// The arguments, in order.
+    pub args: Vec<Arg>,
+    /// A copy of the data passed by pointer, for uploads of buffers, textures and shader sources.
+    pub data: Option<Vec<u8>>,
+}
+
+impl Call {
+    /// Gets an integer argument, or panics if it is not an integer.
+    pub fn int(&self, index: usize) -> i64 {
+        match self.args[index] {
+            Arg::Int(x) => x,
+            ref arg => panic!("Argument {} of {} is {:?}, not an integer", index, self.name, arg),
+        }
+    }
+}
+
+// Serializes tests using the global function pointers.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Locks the global OpenGL function pointers, so other tests can not load them meanwhile.
+pub(crate) fn lock() -> MutexGuard<'static, ()> {
+    // A failed test does not leave the function pointers in a bad state.
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+struct State {
+    calls: Vec<Call>,
+    next_id: GLuint,
+    integers: HashMap<GLenum, Vec<GLint>>,
+    strings: HashMap<GLenum, CString>,
+    locations: HashMap<(GLuint, Vec<u8>), GLint>,
+    error: GLenum,
+    // Whether `glGetError` keeps returning the error, as lost contexts may.
+    error_persists: bool,
+}
+
+impl State {
+    fn new() -> State {
+        let mut strings = HashMap::new();
+        strings.insert(gl::VENDOR, CString::new("opengles_graphics").unwrap());
+        strings.insert(gl::RENDERER, CString::new("Mock").unwrap());
+        strings.insert(gl::VERSION, CString::new("OpenGL ES 3.0 Mock").unwrap());
+        strings.insert(gl::SHADING_LANGUAGE_VERSION,
+                       CString::new("OpenGL ES GLSL ES 3.00").unwrap());
+        strings.insert(gl::EXTENSIONS, CString::new("").unwrap());
+        let mut integers = HashMap::new();
+        integers.insert(gl::PACK_ALIGNMENT, vec![4]);
+        integers.insert(gl::UNPACK_ALIGNMENT, vec![4]);
+        integers.insert(gl::MAX_TEXTURE_SIZE, vec![4096]);
+        integers.insert(gl::STENCIL_BITS, vec![8]);
+        State {
+            calls: Vec::new(),
+            next_id: 1,
+            integers: integers,
+            strings: strings,
+            locations: HashMap::new(),
+            error: gl::NO_ERROR,
+            error_persists: false,
+        }
+    }
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::new());
+}
+
+fn record(name: &'static str, args: Vec<Arg>, data: Option<Vec<u8>>) {
+    STATE.with(|state| {
+        state.borrow_mut().calls.push(Call {
+            name: name,
+            args: args,
+            data: data,
+        })
+    });
+}
+
+fn next_id() -> GLuint {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        id
+    })
+}
+
+fn integer(pname: GLenum) -> GLint {
+    STATE.with(|state| state.borrow().integers.get(&pname).map_or(0, |v| v[0]))
+}
+
+fn set_integers(pname: GLenum, values: Vec<GLint>) {
+    STATE.with(|state| {
+        state.borrow_mut().integers.insert(pname, values);
+    });
+}
+
+unsafe fn copy(data: *const c_void, len: usize) -> Option<Vec<u8>> {
+    if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(data as *const u8, len).to_vec())
+    }
+}
+
+// Gets the size of unsigned byte pixel data with the current unpack alignment.
+fn pixels_size(width: GLsizei, height: GLsizei, format: GLenum) -> usize {
+    let bytes = match format {
+        gl::ALPHA | gl::LUMINANCE | gl::RED => 1,
+        gl::LUMINANCE_ALPHA | gl::RG => 2,
+        gl::RGB => 3,
+        _ => 4,
+    };
+    let alignment = integer(gl::UNPACK_ALIGNMENT).max(1) as usize;
+    let (width, height) = (width.max(0) as usize, height.max(0) as usize);
+    let row = width * bytes;
+    if height == 0 {
+        0
+    } else {
+        (row + alignment - 1) / alignment * alignment * (height - 1) + row
+    }
+}
+
+/// Gets the stub of an OpenGL function, for use with `gl::load_with`.
+pub fn get_proc_address(name: &str) -> *const c_void {
+    stubs::lookup(name)
+}
+
+/// Loads recording OpenGL functions for the lifetime of the value.
+///
+/// Calls are recorded on the thread that created the value,
+/// and other tests loading function pointers wait until it is dropped.
+/// Function pointers are unloaded again when dropped.
+pub struct MockGl {
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl MockGl {
+    /// Loads the recording functions, starting with no recorded calls.
+    pub fn new() -> MockGl {
+        let lock = lock();
+        STATE.with(|state| *state.borrow_mut() = State::new());
+        crate::capabilities::reset();
+        gl::load_with(get_proc_address);
+        MockGl { _lock: lock }
+    }
+
+    /// Gets all recorded calls, in order.
+    pub fn calls(&self) -> Vec<Call> {
+        STATE.with(|state| state.borrow().calls.clone())
+    }
+
+    /// Gets the recorded calls to the named function, in order.
+    pub fn calls_to(&self, name: &str) -> Vec<Call> {
+        STATE.with(|state| state.borrow().calls.iter().filter(|c| c.name == name).cloned().collect())
+    }
+
+    /// Counts the recorded calls to the named function.
+    pub fn count(&self, name: &str) -> usize {
+        STATE.with(|state| state.borrow().calls.iter().filter(|c| c.name == name).count())
+    }
+
+    /// Forgets the recorded calls, keeping the simulated state.
+    pub fn clear(&self) {
+        STATE.with(|state| state.borrow_mut().calls.clear());
+    }
+
+    /// Leaves the named functions unloaded, as on contexts lacking them.
+    ///
+    /// Like strings, this must be done before creating `GlGraphics`.
+    pub fn unload(&self, names: &[&str]) {
+        gl::load_with(|name| {
+            if names.contains(&name) {
+                ptr::null()
+            } else {
+                get_proc_address(name)
+            }
+        });
+    }
+
+    /// Sets the values returned by `glGetIntegerv` for `pname`.
+    pub fn set_integer(&self, pname: GLenum, values: &[GLint]) {
+        set_integers(pname, values.to_vec());
+    }
+
+    /// Sets the error returned by the next call to `glGetError`.
+    pub fn set_error(&self, code: GLenum) {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.error = code;
+            state.error_persists = false;
+        });
+    }
+
+    /// Sets the error returned by every call to `glGetError`, until another is set.
+    pub fn set_persistent_error(&self, code: GLenum) {
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            state.error = code;
+            state.error_persists = true;
+        });
+    }
+
+    /// Sets the string returned by `glGetString` for `name`, such as the extensions.
+    ///
+    /// The capabilities of the context are queried when creating `GlGraphics`,
+    /// so strings describing them must be set before.
+    pub fn set_string(&self, name: GLenum, value: &str) {
+        let value = CString::new(value).expect("String contains a nul byte");
+        STATE.with(|state| {
+            state.borrow_mut().strings.insert(name, value);
+        });
+    }
+}
+
+impl Default for MockGl {
+    fn default() -> MockGl {
+        MockGl::new()
+    }
+}
+
+impl Drop for MockGl {
+    fn drop(&mut self) {
+        gl::load_with(|_| ptr::null());
+    }
+}
+
+// Functions with behavior beyond recording.
+// Their signatures must match the generated bindings.
+
+extern "system" fn GetError() -> GLenum {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if state.error_persists {
+            state.error
+        } else {
+            ::std::mem::replace(&mut state.error, gl::NO_ERROR)
+        }
+    })
+}
+
+unsafe fn gen_ids(n: GLsizei, ids: *mut GLuint) {
+    for i in 0..n.max(0) as usize {
+        *ids.add(i) = next_id();
+    }
+}
+
+extern "system" fn CreateShader(type_: GLenum) -> GLuint {
+    record("glCreateShader", vec![Arg::Int(type_ as i64)], None);
+    next_id()
+}
+
+extern "system" fn CreateProgram() -> GLuint {
+    record("glCreateProgram", vec![], None);
+    next_id()
+}
+
+// Shaders compile and programs link, without an info log.
+unsafe fn object_parameter(pname: GLenum, params: *mut GLint) {
+    *params = match pname {
+        gl::COMPILE_STATUS | gl::LINK_STATUS => gl::TRUE as GLint,
+        _ => 0,
+    };
+}
+
+extern "system" fn GetShaderiv(shader: GLuint, pname: GLenum, params: *mut GLint) {
+    record("glGetShaderiv",
+           vec![Arg::Int(shader as i64), Arg::Int(pname as i64), Arg::Ptr(params as usize)],
+           None);
+    unsafe { object_parameter(pname, params) }
+}
+
+extern "system" fn GetProgramiv(program: GLuint, pname: GLenum, params: *mut GLint) {
+    record("glGetProgramiv",
+           vec![Arg::Int(program as i64), Arg::Int(pname as i64), Arg::Ptr(params as usize)],
+           None);
+    unsafe { object_parameter(pname, params) }
+}
+
+extern "system" fn GetIntegerv(pname: GLenum, data: *mut GLint) {
+    record("glGetIntegerv",
+           vec![Arg::Int(pname as i64), Arg::Ptr(data as usize)],
+           None);
+    STATE.with(|state| {
+        let state = state.borrow();
+        let values = state.integers.get(&pname).map_or(&[0][..], |v| &v[..]);
+        for (i, value) in values.iter().enumerate() {
+            unsafe {
+                *data.add(i) = *value;
+            }
+        }
+    });
+}
+
+extern "system" fn GetString(name: GLenum) -> *const GLubyte {
+    record("glGetString", vec![Arg::Int(name as i64)], None);
+    // The string stays alive until the state is reset by the next `MockGl`.
+    STATE.with(|state| {
+        state.borrow().strings.get(&name).map_or(ptr::null(), |s| s.as_ptr() as *const GLubyte)
+    })
+}
+
+// Gives each name in a program a location of its own.
+fn location(call: &'static str, program: GLuint, name: *const GLchar) -> GLint {
+    let args = vec![Arg::Int(program as i64), Arg::Ptr(name as usize)];
+    let name = unsafe { CStr::from_ptr(name).to_bytes().to_vec() };
+    record(call, args, Some(name.clone()));
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let next = state.locations.len() as GLint;
+        *state.locations.entry((program, name)).or_insert(next)
+    })
+}
+
+extern "system" fn GetAttribLocation(program: GLuint, name: *const GLchar) -> GLint {
+    location("glGetAttribLocation", program, name)
+}
+
+extern "system" fn GetUniformLocation(program: GLuint, name: *const GLchar) -> GLint {
+    location("glGetUniformLocation", program, name)
+}
+
+extern "system" fn CheckFramebufferStatus(target: GLenum) -> GLenum {
+    record("glCheckFramebufferStatus", vec![Arg::Int(target as i64)], None);
+    gl::FRAMEBUFFER_COMPLETE
+}
+
+// Queries finish immediately, measuring nothing.
+extern "system" fn GetQueryObjectuiv(id: GLuint, pname: GLenum, params: *mut GLuint) {
+    record("glGetQueryObjectuiv",
+           vec![Arg::Int(id as i64), Arg::Int(pname as i64), Arg::Ptr(params as usize)],
+           None);
+    unsafe {
+        *params = if pname == gl::QUERY_RESULT_AVAILABLE { 1 } else { 0 };
+    }
+}
+
+extern "system" fn BufferData(target: GLenum,
+                              size: GLsizeiptr,
+                              data: *const c_void,
+                              usage: GLenum) {
+    let copy = unsafe { copy(data, size as usize) };
+    record("glBufferData",
+           vec![Arg::Int(target as i64),
+                Arg::Int(size as i64),
+                Arg::Ptr(data as usize),
+                Arg::Int(usage as i64)],
+           copy);
+}
+
+extern "system" fn BufferSubData(target: GLenum,
+                                 offset: GLintptr,
+                                 size: GLsizeiptr,
+                                 data: *const c_void) {
+    let copy = unsafe { copy(data, size as usize) };
+    record("glBufferSubData",
+           vec![Arg::Int(target as i64),
+                Arg::Int(offset as i64),
+                Arg::Int(size as i64),
+                Arg::Ptr(data as usize)],
+           copy);
+}
+
+extern "system" fn TexImage2D(target: GLenum,
+                              level: GLint,
+                              internalformat: GLint,
+                              width: GLsizei,
+                              height: GLsizei,
+                              border: GLint,
+                              format: GLenum,
+                              type_: GLenum,
+                              pixels: *const c_void) {
+    let copy = unsafe { copy(pixels, pixels_size(width, height, format)) };
+    record("glTexImage2D",
+           vec![Arg::Int(target as i64),
+                Arg::Int(level as i64),
+                Arg::Int(internalformat as i64),
+                Arg::Int(width as i64),
+                Arg::Int(height as i64),
+                Arg::Int(border as i64),
+                Arg::Int(format as i64),
+                Arg::Int(type_ as i64),
+                Arg::Ptr(pixels as usize)],
+           copy);
+}
+
+extern "system" fn TexSubImage2D(target: GLenum,
+                                 level: GLint,
+                                 xoffset: GLint,
+                                 yoffset: GLint,
+                                 width: GLsizei,
+                                 height: GLsizei,
+                                 format: GLenum,
+                                 type_: GLenum,
+                                 pixels: *const c_void) {
+    let copy = unsafe { copy(pixels, pixels_size(width, height, format)) };
+    record("glTexSubImage2D",
+           vec![Arg::Int(target as i64),
+                Arg::Int(level as i64),
+                Arg::Int(xoffset as i64),
+                Arg::Int(yoffset as i64),
+                Arg::Int(width as i64),
+                Arg::Int(height as i64),
+                Arg::Int(format as i64),
+                Arg::Int(type_ as i64),
+                Arg::Ptr(pixels as usize)],
+           copy);
+}
+
+extern "system" fn CompressedTexImage2D(target: GLenum,
+                                        level: GLint,
+                                        internalformat: GLenum,
+                                        width: GLsizei,
+                                        height: GLsizei,
+                                        border: GLint,
+                                        imageSize: GLsizei,
+                                        data: *const c_void) {
+    let copy = unsafe { copy(data, imageSize.max(0) as usize) };
+    record("glCompressedTexImage2D",
+           vec![Arg::Int(target as i64),
+                Arg::Int(level as i64),
+                Arg::Int(internalformat as i64),
+                Arg::Int(width as i64),
+                Arg::Int(height as i64),
+                Arg::Int(border as i64),
+                Arg::Int(imageSize as i64),
+                Arg::Ptr(data as usize)],
+           copy);
+}
+
+extern "system" fn CompressedTexSubImage2D(target: GLenum,
+                                           level: GLint,
+                                           xoffset: GLint,
+                                           yoffset: GLint,
+                                           width: GLsizei,
+                                           height: GLsizei,
+                                           format: GLenum,
+                                           imageSize: GLsizei,
+                                           data: *const c_void) {
+    let copy = unsafe { copy(data, imageSize.max(0) as usize) };
+    record("glCompressedTexSubImage2D",
+           vec![Arg::Int(target as i64),
+                Arg::Int(level as i64),
+                Arg::Int(xoffset as i64),
+                Arg::Int(yoffset as i64),
+                Arg::Int(width as i64),
+                Arg::Int(height as i64),
+                Arg::Int(format as i64),
+                Arg::Int(imageSize as i64),
+                Arg::Ptr(data as usize)],
+           copy);
+}
+
+// Records the concatenated source strings.
+extern "system" fn ShaderSource(shader: GLuint,
+                                count: GLsizei,
+                                string: *const *const GLchar,
+                                length: *const GLint) {
+    let mut source = Vec::new();
+    for i in 0..count.max(0) as usize {
+        unsafe {
+            let s = *string.add(i);
+            if length.is_null() || *length.add(i) < 0 {
+                source.extend_from_slice(CStr::from_ptr(s).to_bytes());
+            } else {
+                source.extend_from_slice(slice::from_raw_parts(s as *const u8,
+                                                               *length.add(i) as usize));
+            }
+        }
+    }
+    record("glShaderSource",
+           vec![Arg::Int(shader as i64),
+                Arg::Int(count as i64),
+                Arg::Ptr(string as usize),
+                Arg::Ptr(length as usize)],
+           Some(source));
+}
+
+extern "system" fn PixelStorei(pname: GLenum, param: GLint) {
+    record("glPixelStorei",
+           vec![Arg::Int(pname as i64), Arg::Int(param as i64)],
+           None);
+    set_integers(pname, vec![param]);
+}
+
+extern "system" fn BindFramebuffer(target: GLenum, framebuffer: GLuint) {
+    record("glBindFramebuffer",
+           vec![Arg::Int(target as i64), Arg::Int(framebuffer as i64)],
+           None);
+    set_integers(gl::FRAMEBUFFER_BINDING, vec![framebuffer as GLint]);
+}
+
+extern "system" fn Viewport(x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+    record("glViewport",
+           vec![Arg::Int(x as i64),
+                Arg::Int(y as i64),
+                Arg::Int(width as i64),
+                Arg::Int(height as i64)],
+           None);
+    set_integers(gl::VIEWPORT, vec![x, y, width, height]);
+}
+
+// Reads transparent black RGBA pixels.
+extern "system" fn ReadPixels(x: GLint,
+                              y: GLint,
+                              width: GLsizei,
+                              height: GLsizei,
+                              format: GLenum,
+                              type_: GLenum,
+                              pixels: *mut c_void) {
+    record("glReadPixels",
+           vec![Arg::Int(x as i64),
+                Arg::Int(y as i64),
+                Arg::Int(width as i64),
+                Arg::Int(height as i64),
+                Arg::Int(format as i64),
+                Arg::Int(type_ as i64),
+                Arg::Ptr(pixels as usize)],
+           None);
+    let len = (width.max(0) * height.max(0) * 4) as usize;
+    unsafe {
+        ptr::write_bytes(pixels as *mut u8, 0, len);
+    }
+}
+
+#[test]
+fn test_records_calls() {
+    let mock = MockGl::new();
+    let mut ids = [0; 2];
+    unsafe {
+        gl::GenTextures(2, ids.as_mut_ptr());
+        gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+        gl::TexImage2D(gl::TEXTURE_2D,
+                       0,
+                       gl::RGB as GLint,
+                       1,
+                       2,
+                       0,
+                       gl::RGB,
+                       gl::UNSIGNED_BYTE,
+                       [1u8, 2, 3, 4, 5, 6].as_ptr() as *const _);
+    }
+    assert_eq!(ids, [1, 2]);
+    assert_eq!(mock.count("glGenTextures"), 1);
+    let uploads = mock.calls_to("glTexImage2D");
+    assert_eq!(uploads[0].int(3), 1);
+    assert_eq!(uploads[0].data, Some(vec![1, 2, 3, 4, 5, 6]));
+}
@@ -0,0 +1,15 @@
+//! Recording stubs for every OpenGL function, generated by `build.rs` from the same
+//! registry as `gl.rs`.
+//!
+//! Functions with behavior beyond recording, such as creating ids, live in the parent module
+//! and are listed in `MOCKED` in `build.rs`.
+
+#![allow(non_snake_case, unused_variables, clippy::too_many_arguments)]
+
+use std::os::raw::{self, c_void};
+use std::ptr;
+
+use crate::gl::types;
+use super::{record, Arg};
+
+include!(concat!(env!("OUT_DIR"), "/stubs.rs"));
@@ -1,14 +1,308 @@
-use crate::gl;
+use crate::checked_gl as gl;
 use crate::gl::types::GLuint;
 use image::{self, DynamicImage, RgbaImage};
+use graphics::color::{gamma_linear_to_srgb, gamma_srgb_to_linear};
 
+use std::cell::{Cell, RefCell};
+use std::rc::{Rc, Weak};
+use std::fs::File;
+use std::io::Read;
 use std::path::Path;
 
-use crate::{ops, ImageSize, CreateTexture, UpdateTexture, TextureSettings, Format, Filter};
+use crate::capabilities;
+use crate::color_space::{self, ColorSpace};
+use crate::context;
+use crate::ktx;
+use crate::error::{check_gl_error, Error};
+use crate::render_target::read_rgba;
+
+use crate::{ImageSize, CreateTexture, UpdateTexture, TextureSettings, Format, Filter};
+
+/// Pixel formats of texture data.
+///
+/// Single-channel formats are sampled as coverage,
+/// which tints them with the draw color like an alpha mask.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextureFormat {
+    /// 8-bit alpha, sampled as coverage.
+    Alpha8,
+    /// 8-bit luminance, sampled as coverage.
+    Luminance8,
+    /// 8-bit luminance and alpha.
+    LuminanceAlpha8,
+    /// 8-bit red, sampled as coverage. Requires OpenGL ES 3.0.
+    R8,
+    /// 8-bit red and green. Requires OpenGL ES 3.0.
+    Rg8,
+    /// 8-bit red, green and blue.
+    Rgb8,
+    /// 8-bit red, green, blue and alpha.
+    Rgba8,
+    /// Compressed data with the given OpenGL internal format,
+    /// which can not be updated.
+    Compressed(gl::types::GLenum),
+}
+
+impl TextureFormat {
+    /// Gets the number of bytes per pixel, or `None` for compressed formats.
+    pub fn bytes_per_pixel(&self) -> Option<u32> {
+        match *self {
+            TextureFormat::Alpha8 | TextureFormat::Luminance8 | TextureFormat::R8 => Some(1),
+            TextureFormat::LuminanceAlpha8 | TextureFormat::Rg8 => Some(2),
+            TextureFormat::Rgb8 => Some(3),
+            TextureFormat::Rgba8 => Some(4),
+            TextureFormat::Compressed(_) => None,
+        }
+    }
+
+    // Returns `true` if textures of the format, stored as sRGB or not,
+    // can be attached to a framebuffer.
+    // This depends on the internal format, as core profiles store luminance and alpha in red.
+    fn is_color_renderable(&self, srgb: bool) -> bool {
+        // Unlike `SRGB8_ALPHA8`, `SRGB8` is not color-renderable in OpenGL ES 3.0.
+        match self.get_gl_formats(srgb).0 {
+            gl::R8 | gl::RG8 | gl::RGB | gl::RGBA | gl::SRGB8_ALPHA8 => true,
+            _ => false,
+        }
+    }
+
+    /// Gets the index of the sampled channel holding coverage,
+    /// or `None` if the format is sampled as color.
+    ///
+    /// Alpha textures of core profiles without texture swizzle are sampled from red.
+    pub fn coverage_channel(&self) -> Option<usize> {
+        match *self {
+            TextureFormat::Alpha8 if self.in_red_green() && self.get_swizzle().is_none() => {
+                Some(0)
+            }
+            TextureFormat::Alpha8 => Some(3),
+            TextureFormat::Luminance8 | TextureFormat::R8 => Some(0),
+            _ => None,
+        }
+    }
+
+    // Returns `true` if the format is stored in red and green,
+    // as core profiles lack luminance and alpha formats.
+    fn in_red_green(&self) -> bool {
+        match *self {
+            TextureFormat::Alpha8 |
+            TextureFormat::Luminance8 |
+            TextureFormat::LuminanceAlpha8 => capabilities::current().core_profile,
+            _ => false,
+        }
+    }
+
+    // Gets the swizzle sampling red and green as luminance and alpha,
+    // if the format is stored in them and the context supports swizzling.
+    fn get_swizzle(&self) -> Option<[gl::types::GLenum; 4]> {
+        if !self.in_red_green() || !capabilities::current().texture_swizzle {
+            return None;
+        }
+        match *self {
+            TextureFormat::Alpha8 => Some([gl::ZERO, gl::ZERO, gl::ZERO, gl::RED]),
+            TextureFormat::Luminance8 => Some([gl::RED, gl::RED, gl::RED, gl::ONE]),
+            TextureFormat::LuminanceAlpha8 => Some([gl::RED, gl::RED, gl::RED, gl::GREEN]),
+            _ => None,
+        }
+    }
+
+    // Gets the internal format and the format of pixel data,
+    // storing colors as sRGB if `srgb` is set.
+    fn get_gl_formats(&self, srgb: bool) -> (gl::types::GLenum, gl::types::GLenum) {
+        if self.in_red_green() {
+            return match *self {
+                TextureFormat::LuminanceAlpha8 => (gl::RG8, gl::RG),
+                _ => (gl::R8, gl::RED),
+            };
+        }
+        match *self {
+            TextureFormat::Alpha8 => (gl::ALPHA, gl::ALPHA),
+            TextureFormat::Luminance8 => (gl::LUMINANCE, gl::LUMINANCE),
+            TextureFormat::LuminanceAlpha8 => (gl::LUMINANCE_ALPHA, gl::LUMINANCE_ALPHA),
+            TextureFormat::R8 => (gl::R8, gl::RED),
+            TextureFormat::Rg8 => (gl::RG8, gl::RG),
+            TextureFormat::Rgb8 if srgb => (gl::SRGB8, gl::RGB),
+            TextureFormat::Rgb8 => (gl::RGB, gl::RGB),
+            TextureFormat::Rgba8 if srgb => (gl::SRGB8_ALPHA8, gl::RGBA),
+            TextureFormat::Rgba8 => (gl::RGBA, gl::RGBA),
+            TextureFormat::Compressed(format) => (format, format),
+        }
+    }
+}
+
+/// How the color channels of a texture relate to its alpha.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// Colors are independent of alpha.
+    Straight,
+    /// Colors are multiplied with alpha, which filters without dark fringes.
+    ///
+    /// Such textures are drawn with `Blend::Alpha` as premultiplied alpha,
+    /// and with the tint color premultiplied too.
+    Premultiplied,
+}
+
+/// Multiplies the color channels of RGBA pixels with their alpha.
+pub fn premultiply_alpha(pixels: &mut [u8]) {
+    for pixel in pixels.chunks_mut(4) {
+        if pixel.len() < 4 {
+            break;
+        }
+        let alpha = pixel[3] as u32;
+        for channel in &mut pixel[..3] {
+            // Rounds to the nearest value.
+            *channel = ((*channel as u32 * alpha + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Multiplies the color channels of RGBA pixels with their alpha in linear space,
+/// keeping them sRGB encoded.
+///
+/// This matches textures stored as sRGB, see `Texture::is_srgb`,
+/// which are converted to linear before the premultiplied colors are blended.
+pub fn premultiply_alpha_linear(pixels: &mut [u8]) {
+    for pixel in pixels.chunks_mut(4) {
+        if pixel.len() < 4 {
+            break;
+        }
+        let alpha = pixel[3] as f32 / 255.0;
+        let color = [pixel[0] as f32 / 255.0,
+                     pixel[1] as f32 / 255.0,
+                     pixel[2] as f32 / 255.0,
+                     1.0];
+        let linear = gamma_srgb_to_linear(color);
+        let color = gamma_linear_to_srgb([linear[0] * alpha,
+                                          linear[1] * alpha,
+                                          linear[2] * alpha,
+                                          1.0]);
+        for (channel, value) in pixel[..3].iter_mut().zip(color.iter()) {
+            *channel = (value * 255.0).round() as u8;
+        }
+    }
+}
+
+/// Wrapping of texture coordinates outside of `[0, 1]`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wrap {
+    /// Repeat the edge pixels.
+    ClampToEdge,
+    /// Repeat the texture.
+    Repeat,
+    /// Repeat the texture, mirroring every other repetition.
+    MirroredRepeat,
+}
+
+impl Wrap {
+    fn get_gl_wrap(&self) -> gl::types::GLenum {
+        match *self {
+            Wrap::ClampToEdge => gl::CLAMP_TO_EDGE,
+            Wrap::Repeat => gl::REPEAT,
+            Wrap::MirroredRepeat => gl::MIRRORED_REPEAT,
+        }
+    }
+}
+
+impl From<Format> for TextureFormat {
+    fn from(format: Format) -> TextureFormat {
+        match format {
+            Format::Rgba8 => TextureFormat::Rgba8,
+        }
+    }
+}
+
+// Checks that the context can sample the format as intended.
+// Luminance and alpha textures of core profiles need swizzling, unless sampled as coverage.
+fn check_format(format: TextureFormat) -> Result<(), Error> {
+    if format == TextureFormat::LuminanceAlpha8 && format.in_red_green() &&
+       format.get_swizzle().is_none() {
+        Err(Error::UnsupportedFormat(format!("{:?} requires OpenGL 3.3 or \
+                                              GL_ARB_texture_swizzle in core profiles",
+                                             format)))
+    } else {
+        Ok(())
+    }
+}
+
+// Checks that `memory` holds enough uncompressed pixels.
+fn check_memory_size(format: TextureFormat, memory: &[u8], size: [u32; 2]) -> Result<(), Error> {
+    let bytes_per_pixel = match format.bytes_per_pixel() {
+        Some(bytes) => bytes,
+        None => {
+            return Err(Error::UnsupportedFormat(format!("Expected uncompressed pixels, got {:?}",
+                                                        format)))
+        }
+    };
+    let expected = (size[0] * size[1] * bytes_per_pixel) as usize;
+    if memory.len() < expected {
+        Err(Error::InvalidData(format!("Expected {} bytes of {:?} pixels, got {}",
+                                       expected,
+                                       format,
+                                       memory.len())))
+    } else {
+        Ok(())
+    }
+}
+
+// Checks that the context can decode a compressed format.
+fn check_compressed_format(format: gl::types::GLenum) -> Result<(), Error> {
+    if capabilities::current().supports_compressed_format(format) {
+        Ok(())
+    } else {
+        Err(Error::UnsupportedFormat(format!("Compressed format 0x{:X} is not supported \
+                                              by the OpenGL context",
+                                             format)))
+    }
+}
+
+// Gets the size of a mip level.
+fn level_size(size: [u32; 2], level: usize) -> [u32; 2] {
+    [(size[0] >> level).max(1), (size[1] >> level).max(1)]
+}
+
+// Gets the number of levels of a full mip chain, down to 1x1.
+fn mip_level_count(size: [u32; 2]) -> usize {
+    (32 - size[0].max(size[1]).max(1).leading_zeros()) as usize
+}
+
+// Returns `true` if a texture with `levels` uploaded mip levels can be sampled with mipmapping.
+// Chains stopping early, such as at the block size of compressed formats, are only complete
+// when limited with `TEXTURE_MAX_LEVEL`. Without it, they are sampled from the base level.
+fn samples_mip_levels(levels: usize, size: [u32; 2]) -> bool {
+    levels > 1 && (levels >= mip_level_count(size) || capabilities::current().texture_max_level)
+}
+
+// Returns `true` if color textures created now are stored as sRGB,
+// which requires OpenGL ES 3.0 and a linear color space.
+fn use_srgb(format: TextureFormat, generate_mipmaps: bool) -> bool {
+    if color_space::current() != ColorSpace::Linear {
+        return false;
+    }
+    // `SRGB8` is not renderable, which generating mipmaps requires.
+    let format_supported = match format {
+        TextureFormat::Rgba8 => true,
+        TextureFormat::Rgb8 => !generate_mipmaps,
+        _ => false,
+    };
+    if !format_supported {
+        return false;
+    }
+    capabilities::current().has_es3_features()
+}
+
+// Runs `f` with tightly packed rows, since rows of 1 to 3 byte pixels are not 4 byte aligned.
+unsafe fn with_unpack_alignment<F: FnOnce()>(f: F) {
+    let mut alignment = 0;
+    gl::GetIntegerv(gl::UNPACK_ALIGNMENT, &mut alignment);
+    gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+    f();
+    gl::PixelStorei(gl::UNPACK_ALIGNMENT, alignment);
+}
 
 trait GlSettings {
     fn get_gl_mag(&self) -> gl::types::GLenum;
     fn get_gl_min(&self) -> gl::types::GLenum;
+    fn get_gl_min_with_mipmaps(&self, mipmaps: bool) -> gl::types::GLenum;
     fn get_gl_mipmap(&self) -> gl::types::GLenum;
 }
 
@@ -21,9 +315,13 @@ impl GlSettings for TextureSettings {
     }
 
     fn get_gl_min(&self) -> gl::types::GLenum {
+        self.get_gl_min_with_mipmaps(self.get_generate_mipmap())
+    }
+
+    fn get_gl_min_with_mipmaps(&self, mipmaps: bool) -> gl::types::GLenum {
         match self.get_min() {
             Filter::Linear => {
-                if self.get_generate_mipmap() {
+                if mipmaps {
                     match self.get_mipmap() {
                         Filter::Linear => gl::LINEAR_MIPMAP_LINEAR,
                         Filter::Nearest => gl::LINEAR_MIPMAP_NEAREST,
@@ -33,7 +331,7 @@ impl GlSettings for TextureSettings {
                 }
             }
             Filter::Nearest => {
-                if self.get_generate_mipmap() {
+                if mipmaps {
                     match self.get_mipmap() {
                         Filter::Linear => gl::NEAREST_MIPMAP_LINEAR,
                         Filter::Nearest => gl::NEAREST_MIPMAP_NEAREST,
@@ -54,35 +352,411 @@ impl GlSettings for TextureSettings {
 }
 
 /// Wraps OpenGL texture data.
-/// The texture gets deleted when running out of scope.
+/// The texture gets deleted when running out of scope,
+/// or after `GlGraphics` draws the vertices batched with it.
 ///
 /// In order to create a texture the function `GenTextures` must be loaded.
 /// This is done automatically by the window back-ends in Piston.
+///
+/// `GlGraphics` batches draws until the texture or state changes, so changing the contents,
+/// mipmaps or wrapping of a texture also changes how its batched draws look.
+/// Call `GlGraphics::flush` before changing a texture drawn earlier in the frame.
+///
+/// `GlGraphics::recreate_after_context_loss` recreates a texture under the same handle
+/// with its reload function, see `set_reload`, or from a copy of its pixel data,
+/// kept for textures created while `GlGraphics::set_keep_texture_pixels` is enabled.
+/// Other textures keep their stale id.
 pub struct Texture {
-    id: GLuint,
+    // Shared with the registry of textures to recreate.
+    record: Rc<TextureRecord>,
+    lazy_mipmaps: bool,
+    mipmaps_dirty: Cell<bool>,
+    // The rectangle `[x, y, w, h]` drawn, for glyphs sharing an atlas texture.
+    region: Option<[u32; 4]>,
+}
+
+// The state needed to recreate a texture in a new context.
+struct TextureRecord {
+    id: Cell<GLuint>,
+    // The context generation the id belongs to.
+    generation: Cell<u32>,
     width: u32,
     height: u32,
+    format: TextureFormat,
+    // Whether colors are stored as sRGB.
+    srgb: bool,
+    // The minification and magnification filters.
+    filters: [gl::types::GLenum; 2],
+    wrap: Cell<[Wrap; 2]>,
+    alpha_mode: Cell<AlphaMode>,
+    mipmapped: bool,
+    source: RefCell<Source>,
+}
+
+// Where to get the texture data from when recreating the texture.
+enum Source {
+    // The texture is not recreated, such as when created from an existing id.
+    None,
+    // A copy of each mip level, starting with the base level.
+    Levels(Vec<Vec<u8>>),
+    // Creates a texture with the same contents.
+    Reload(Box<dyn Fn() -> Result<Texture, Error>>),
+}
+
+thread_local! {
+    // The textures of the current thread, which owns the context.
+    static TEXTURES: RefCell<Vec<Weak<TextureRecord>>> = RefCell::new(Vec::new());
+    // Whether textures created from pixel data keep a copy of it.
+    static KEEP_PIXELS: Cell<bool> = Cell::new(false);
+}
+
+/// Sets whether textures created from now on, on the current thread,
+/// keep a copy of their pixel data to be recreated from.
+pub(crate) fn set_keep_pixels(keep: bool) {
+    KEEP_PIXELS.with(|k| k.set(keep));
+}
+
+// Copies the levels if textures keep their pixel data.
+fn levels_source<T: AsRef<[u8]>>(levels: &[T]) -> Source {
+    if KEEP_PIXELS.with(|k| k.get()) {
+        Source::Levels(levels.iter().map(|level| level.as_ref().to_vec()).collect())
+    } else {
+        Source::None
+    }
+}
+
+fn register(record: &Rc<TextureRecord>) {
+    TEXTURES.with(|textures| {
+        let mut textures = textures.borrow_mut();
+        // Forget dropped textures before growing, so the list follows the live ones.
+        if textures.len() == textures.capacity() {
+            textures.retain(|t| t.upgrade().is_some());
+        }
+        textures.push(Rc::downgrade(record));
+    });
+}
+
+/// Recreates the textures of a lost context in the current one.
+///
+/// Textures created from an existing id keep their stale id.
+pub(crate) fn recreate_textures() -> Result<(), Error> {
+    // Reloading creates textures, which registers them.
+    let records: Vec<Rc<TextureRecord>> = TEXTURES.with(|textures| {
+        let mut textures = textures.borrow_mut();
+        textures.retain(|t| t.upgrade().is_some());
+        textures.iter().filter_map(|t| t.upgrade()).collect()
+    });
+    for record in records {
+        record.recreate()?;
+    }
+    Ok(())
+}
+
+/// Keeps the OpenGL texture of a `Texture` alive after the `Texture` is dropped,
+/// while vertices sampling it are batched.
+#[derive(Clone)]
+pub(crate) struct TextureHandle(Rc<TextureRecord>);
+
+impl TextureHandle {
+    /// Gets the OpenGL id of the texture.
+    pub(crate) fn id(&self) -> GLuint {
+        self.0.id.get()
+    }
+}
+
+impl TextureRecord {
+    fn recreate(&self) -> Result<(), Error> {
+        let size = [self.width, self.height];
+        let id = match *self.source.borrow() {
+            Source::None => return Ok(()),
+            Source::Levels(ref levels) => unsafe {
+                upload(levels,
+                       size,
+                       self.format,
+                       self.srgb,
+                       self.filters,
+                       self.wrap.get(),
+                       self.mipmapped)
+            },
+            Source::Reload(ref reload) => {
+                let texture = reload()?;
+                // The texture is sampled the same way as before, so the storage must match too.
+                if [texture.record.width, texture.record.height] != size ||
+                   texture.get_format() != self.format ||
+                   texture.is_srgb() != self.srgb {
+                    let srgb = |srgb| if srgb { "sRGB " } else { "" };
+                    return Err(Error::InvalidData(format!("Reloaded a {}{:?} {}x{} texture, \
+                                                           expected {}{:?} {}x{}",
+                                                          srgb(texture.is_srgb()),
+                                                          texture.get_format(),
+                                                          texture.record.width,
+                                                          texture.record.height,
+                                                          srgb(self.srgb),
+                                                          self.format,
+                                                          self.width,
+                                                          self.height)));
+                }
+                // Take over the id, deleting id 0 is ignored.
+                let id = texture.record.id.replace(0);
+                unsafe {
+                    gl::BindTexture(gl::TEXTURE_2D, id);
+                    set_parameters(self.filters, self.wrap.get());
+                    if self.mipmapped {
+                        gl::GenerateMipmap(gl::TEXTURE_2D);
+                    }
+                }
+                id
+            }
+        };
+        self.id.set(id);
+        self.generation.set(context::generation());
+        check_gl_error("recreating a texture")
+    }
+}
+
+// Sets the filters and wrapping of the bound texture.
+unsafe fn set_parameters(filters: [gl::types::GLenum; 2], wrap: [Wrap; 2]) {
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, filters[0] as i32);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, filters[1] as i32);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, wrap[0].get_gl_wrap() as i32);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, wrap[1].get_gl_wrap() as i32);
+}
+
+// Creates a texture object from the data of each mip level, returning its id.
+unsafe fn upload<T: AsRef<[u8]>>(levels: &[T],
+                                 size: [u32; 2],
+                                 format: TextureFormat,
+                                 srgb: bool,
+                                 filters: [gl::types::GLenum; 2],
+                                 wrap: [Wrap; 2],
+                                 generate_mipmaps: bool)
+                                 -> GLuint {
+    let (internal_format, data_format) = format.get_gl_formats(srgb);
+    let mut id: GLuint = 0;
+    gl::GenTextures(1, &mut id);
+    gl::BindTexture(gl::TEXTURE_2D, id);
+    set_parameters(filters, wrap);
+    if let Some(swizzle) = format.get_swizzle() {
+        let names = [gl::TEXTURE_SWIZZLE_R,
+                     gl::TEXTURE_SWIZZLE_G,
+                     gl::TEXTURE_SWIZZLE_B,
+                     gl::TEXTURE_SWIZZLE_A];
+        for (name, source) in names.iter().zip(swizzle.iter()) {
+            gl::TexParameteri(gl::TEXTURE_2D, *name, *source as i32);
+        }
+    }
+    with_unpack_alignment(|| {
+        for (level, memory) in levels.iter().enumerate() {
+            let memory = memory.as_ref();
+            let size = level_size(size, level);
+            if let TextureFormat::Compressed(_) = format {
+                gl::CompressedTexImage2D(gl::TEXTURE_2D,
+                                         level as i32,
+                                         internal_format,
+                                         size[0] as i32,
+                                         size[1] as i32,
+                                         0,
+                                         memory.len() as i32,
+                                         memory.as_ptr() as *const _);
+            } else {
+                gl::TexImage2D(gl::TEXTURE_2D,
+                               level as i32,
+                               internal_format as i32,
+                               size[0] as i32,
+                               size[1] as i32,
+                               0,
+                               data_format,
+                               gl::UNSIGNED_BYTE,
+                               memory.as_ptr() as *const _);
+            }
+        }
+    });
+    // Mipmaps are built from the uploaded base level.
+    if generate_mipmaps {
+        gl::GenerateMipmap(gl::TEXTURE_2D);
+    } else if levels.len() > 1 && levels.len() < mip_level_count(size) &&
+              capabilities::current().texture_max_level {
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, levels.len() as i32 - 1);
+    }
+    id
 }
 
 impl Texture {
     /// Creates a new texture.
     #[inline(always)]
     pub fn new(id: GLuint, width: u32, height: u32) -> Self {
+        Texture::new_with_format(id, width, height, TextureFormat::Rgba8)
+    }
+
+    /// Creates a new texture with pixels of the given format.
+    ///
+    /// The texture is not recreated after the context is lost, unless `set_reload` is called.
+    #[inline(always)]
+    pub fn new_with_format(id: GLuint, width: u32, height: u32, format: TextureFormat) -> Self {
+        Texture::with_source(id,
+                             [width, height],
+                             format,
+                             false,
+                             [gl::LINEAR, gl::LINEAR],
+                             false,
+                             Source::None)
+    }
+
+    fn with_source(id: GLuint,
+                   size: [u32; 2],
+                   format: TextureFormat,
+                   srgb: bool,
+                   filters: [gl::types::GLenum; 2],
+                   mipmapped: bool,
+                   source: Source)
+                   -> Self {
+        let record = Rc::new(TextureRecord {
+            id: Cell::new(id),
+            generation: Cell::new(context::generation()),
+            width: size[0],
+            height: size[1],
+            format: format,
+            srgb: srgb,
+            filters: filters,
+            wrap: Cell::new([Wrap::ClampToEdge; 2]),
+            alpha_mode: Cell::new(AlphaMode::Straight),
+            mipmapped: mipmapped,
+            source: RefCell::new(source),
+        });
+        register(&record);
         Texture {
-            id: id,
-            width: width,
-            height: height,
+            record: record,
+            lazy_mipmaps: false,
+            mipmaps_dirty: Cell::new(false),
+            region: None,
+        }
+    }
+
+    /// Shares the texture, drawing only the rectangle `[x, y, w, h]` of it.
+    ///
+    /// The size of the region is the size of the returned texture,
+    /// its texture coordinates are mapped into the rectangle when drawing.
+    pub(crate) fn region(&self, rect: [u32; 4]) -> Texture {
+        Texture {
+            record: self.record.clone(),
+            lazy_mipmaps: false,
+            mipmaps_dirty: Cell::new(false),
+            region: Some(rect),
+        }
+    }
+
+    /// Gets the rectangle `[u, v, w, h]` of texture coordinates drawn, if not the whole texture.
+    pub(crate) fn uv_rect(&self) -> Option<[f32; 4]> {
+        self.region.map(|rect| {
+            let (width, height) = (self.record.width as f32, self.record.height as f32);
+            [rect[0] as f32 / width,
+             rect[1] as f32 / height,
+             rect[2] as f32 / width,
+             rect[3] as f32 / height]
+        })
+    }
+
+    // Gets the size of the region drawn.
+    fn size(&self) -> (u32, u32) {
+        match self.region {
+            Some(rect) => (rect[2], rect[3]),
+            None => (self.record.width, self.record.height),
         }
     }
 
     /// Gets the OpenGL id of the texture.
+    ///
+    /// The id changes when the texture is recreated after the context is lost.
     #[inline(always)]
     pub fn get_id(&self) -> GLuint {
-        self.id
+        self.record.id.get()
+    }
+
+    /// Shares the OpenGL texture, which is deleted once the texture and all handles are dropped.
+    pub(crate) fn handle(&self) -> TextureHandle {
+        TextureHandle(self.record.clone())
+    }
+
+    /// Gets the pixel format of the texture.
+    #[inline(always)]
+    pub fn get_format(&self) -> TextureFormat {
+        self.record.format
+    }
+
+    /// Returns `true` if the colors are stored as sRGB and converted to linear when sampled.
+    ///
+    /// Color textures are stored as sRGB when created while `GlGraphics`
+    /// uses the linear color space on OpenGL ES 3.0.
+    #[inline(always)]
+    pub fn is_srgb(&self) -> bool {
+        self.record.srgb
+    }
+
+    /// Gets how the colors of the texture relate to its alpha.
+    #[inline(always)]
+    pub fn get_alpha_mode(&self) -> AlphaMode {
+        self.record.alpha_mode.get()
+    }
+
+    /// Marks the texture as holding straight or premultiplied alpha,
+    /// such as for data premultiplied in advance or by drawing into a render target.
+    ///
+    /// The data is not changed. Coverage textures, such as those from `from_memory_alpha`,
+    /// can be drawn in either mode.
+    pub fn set_alpha_mode(&mut self, alpha_mode: AlphaMode) {
+        self.record.alpha_mode.set(alpha_mode);
+    }
+
+    /// Gets the wrapping of the horizontal and vertical texture coordinates.
+    #[inline(always)]
+    pub fn get_wrap(&self) -> [Wrap; 2] {
+        self.record.wrap.get()
+    }
+
+    /// Sets the wrapping of the horizontal (`s`) and vertical (`t`) texture coordinates.
+    ///
+    /// Returns `Err` when repeating a non-power of two texture on OpenGL ES 2.0
+    /// without `OES_texture_npot`.
+    /// Affects batched draws of the texture, see `GlGraphics::flush`.
+    pub fn set_wrap(&mut self, s: Wrap, t: Wrap) -> Result<(), Error> {
+        let (width, height) = (self.record.width, self.record.height);
+        let repeats = s != Wrap::ClampToEdge || t != Wrap::ClampToEdge;
+        let power_of_two = width.is_power_of_two() && height.is_power_of_two();
+        if repeats && !power_of_two && !capabilities::current().npot_textures() {
+            return Err(Error::Unsupported(format!("Can not repeat a non-power of two {}x{} \
+                                                   texture on OpenGL ES 2.0",
+                                                  width,
+                                                  height)));
+        }
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.get_id());
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, s.get_gl_wrap() as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, t.get_gl_wrap() as i32);
+        }
+        self.record.wrap.set([s, t]);
+        Ok(())
+    }
+
+    /// Sets the wrapping of the texture coordinates, see `set_wrap`.
+    pub fn with_wrap(mut self, s: Wrap, t: Wrap) -> Result<Self, Error> {
+        self.set_wrap(s, t)?;
+        Ok(self)
+    }
+
+    /// Sets the function recreating the texture after the context is lost,
+    /// dropping the copy of the pixel data kept for it, if any.
+    ///
+    /// The function must create a texture of the same size and format,
+    /// stored as sRGB if this one is, see `is_srgb`.
+    /// Changes made to the texture after it was created are not kept.
+    pub fn set_reload<F>(&mut self, reload: F)
+        where F: Fn() -> Result<Texture, Error> + 'static
+    {
+        *self.record.source.borrow_mut() = Source::Reload(Box::new(reload));
     }
 
     /// Returns empty texture.
-    pub fn empty() -> Result<Self, String> {
+    pub fn empty() -> Result<Self, Error> {
         CreateTexture::create(&mut (),
                               Format::Rgba8,
                               &[0u8; 4],
@@ -91,138 +765,635 @@ impl Texture {
     }
 
     /// Loads image from memory, the format is 8-bit greyscale.
+    ///
+    /// The texture is stored with a single alpha channel.
     pub fn from_memory_alpha(buf: &[u8],
                              width: u32,
                              height: u32,
                              settings: &TextureSettings)
-                             -> Result<Self, String> {
+                             -> Result<Self, Error> {
+        Texture::from_memory(buf, width, height, TextureFormat::Alpha8, settings)
+    }
+
+    /// Loads image from memory in the given format.
+    pub fn from_memory(buf: &[u8],
+                       width: u32,
+                       height: u32,
+                       format: TextureFormat,
+                       settings: &TextureSettings)
+                       -> Result<Self, Error> {
+        Texture::create(&[buf],
+                        [width, height],
+                        format,
+                        settings.get_gl_min(),
+                        settings,
+                        settings.get_generate_mipmap())
+    }
+
+    /// Loads image from memory with explicit data for each mip level,
+    /// starting with the `width` x `height` base level.
+    ///
+    /// Each level is half the size of the previous one, rounded down to at least 1.
+    /// The mip levels are not regenerated when the texture is updated.
+    ///
+    /// The levels may stop before 1x1. OpenGL ES 2.0 without `GL_APPLE_texture_max_level`
+    /// can not sample those, so it only samples the base level.
+    pub fn from_memory_levels(levels: &[&[u8]],
+                              width: u32,
+                              height: u32,
+                              format: TextureFormat,
+                              settings: &TextureSettings)
+                              -> Result<Self, Error> {
         let size = [width, height];
-        let buffer = ops::alpha_to_rgba8(buf, size);
-        CreateTexture::create(&mut (), Format::Rgba8, &buffer, size, settings)
+        if levels.is_empty() || levels.len() > mip_level_count(size) {
+            return Err(Error::InvalidData(format!("Expected 1 to {} mip levels, got {}",
+                                                  mip_level_count(size),
+                                                  levels.len())));
+        }
+        Texture::create(levels,
+                        size,
+                        format,
+                        settings.get_gl_min_with_mipmaps(samples_mip_levels(levels.len(), size)),
+                        settings,
+                        false)
+    }
+
+    fn create(levels: &[&[u8]],
+              size: [u32; 2],
+              format: TextureFormat,
+              min_filter: gl::types::GLenum,
+              settings: &TextureSettings,
+              generate_mipmaps: bool)
+              -> Result<Self, Error> {
+        check_format(format)?;
+        for (level, memory) in levels.iter().enumerate() {
+            check_memory_size(format, memory, level_size(size, level))?;
+        }
+        let filters = [min_filter, settings.get_gl_mag()];
+        let srgb = use_srgb(format, generate_mipmaps);
+        let id = unsafe {
+            upload(levels,
+                   size,
+                   format,
+                   srgb,
+                   filters,
+                   [Wrap::ClampToEdge; 2],
+                   generate_mipmaps)
+        };
+
+        let texture = Texture::with_source(id,
+                                           size,
+                                           format,
+                                           srgb,
+                                           filters,
+                                           generate_mipmaps,
+                                           levels_source(levels));
+        // Reports running out of memory or exceeding the maximum size.
+        check_gl_error("glTexImage2D")?;
+        Ok(texture)
+    }
+
+    /// Returns `true` if the mipmaps are regenerated when the texture is updated.
+    #[inline(always)]
+    pub fn has_generated_mipmaps(&self) -> bool {
+        self.record.mipmapped
+    }
+
+    /// Delays regenerating mipmaps after updates until the texture is drawn next.
+    ///
+    /// This avoids regenerating them after each of several updates in a row.
+    pub fn set_lazy_mipmaps(&mut self, lazy: bool) {
+        self.lazy_mipmaps = lazy;
+        if !lazy {
+            self.refresh_mipmaps();
+        }
+    }
+
+    /// Regenerates the mipmaps from the base level.
+    ///
+    /// Affects batched draws of the texture, see `GlGraphics::flush`.
+    pub fn generate_mipmaps(&self) {
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.get_id());
+            gl::GenerateMipmap(gl::TEXTURE_2D);
+        }
+        self.mipmaps_dirty.set(false);
+    }
+
+    /// Regenerates the mipmaps if they are outdated by a lazy update.
+    pub(crate) fn refresh_mipmaps(&self) {
+        if self.mipmaps_dirty.get() {
+            self.generate_mipmaps();
+        }
+    }
+
+    // Called after the base level changed.
+    fn base_level_updated(&self) {
+        if self.record.mipmapped {
+            if self.lazy_mipmaps {
+                self.mipmaps_dirty.set(true);
+            } else {
+                self.generate_mipmaps();
+            }
+        }
+    }
+
+    /// Replaces the data of a mip level, in the format of the texture.
+    ///
+    /// Replacing the base level regenerates generated mipmaps,
+    /// which overwrite the other levels.
+    /// Affects batched draws of the texture, see `GlGraphics::flush`.
+    pub fn upload_level(&mut self, level: u32, memory: &[u8]) -> Result<(), Error> {
+        let format = self.record.format;
+        let size = level_size([self.record.width, self.record.height], level as usize);
+        check_memory_size(format, memory, size)?;
+        let (internal_format, data_format) = format.get_gl_formats(self.record.srgb);
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.get_id());
+            with_unpack_alignment(|| {
+                gl::TexImage2D(gl::TEXTURE_2D,
+                               level as i32,
+                               internal_format as i32,
+                               size[0] as i32,
+                               size[1] as i32,
+                               0,
+                               data_format,
+                               gl::UNSIGNED_BYTE,
+                               memory.as_ptr() as *const _);
+            });
+        }
+        if let Source::Levels(ref mut levels) = *self.record.source.borrow_mut() {
+            let level = level as usize;
+            if level < levels.len() {
+                levels[level] = memory.to_vec();
+            } else if level == levels.len() {
+                levels.push(memory.to_vec());
+            }
+        }
+        if level == 0 {
+            self.base_level_updated();
+        }
+        Ok(())
     }
 
     /// Loads image by relative file name to the asset root.
-    pub fn from_path<P>(path: P) -> Result<Self, String>
+    ///
+    /// After the context is lost, the texture is recreated by loading the file again.
+    pub fn from_path<P>(path: P) -> Result<Self, Error>
         where P: AsRef<Path>
     {
-        let path = path.as_ref();
-
-        let img = match image::open(path) {
-            Ok(img) => img,
-            Err(e) => {
-                return Err(format!("Could not load '{:?}': {:?}", path.file_name().unwrap(), e))
-            }
-        };
+        let path = path.as_ref().to_path_buf();
+        let img = image::open(&path)?;
 
         let img = match img {
             DynamicImage::ImageRgba8(img) => img,
             x => x.to_rgba(),
         };
 
-        Ok(Texture::from_image(&img, &TextureSettings::new()))
+        let mut texture = Texture::from_image(&img, &TextureSettings::new())?;
+        texture.set_reload(move || Texture::from_path(&path));
+        Ok(texture)
+    }
+
+    /// Loads image by relative file name to the asset root,
+    /// premultiplying the colors with alpha.
+    ///
+    /// After the context is lost, the texture is recreated by loading the file again.
+    pub fn from_path_premultiplied<P>(path: P) -> Result<Self, Error>
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref().to_path_buf();
+        let img = image::open(&path)?.to_rgba();
+
+        let mut texture = Texture::from_image_premultiplied(&img, &TextureSettings::new())?;
+        texture.set_reload(move || Texture::from_path_premultiplied(&path));
+        Ok(texture)
+    }
+
+    /// Loads a compressed texture from a KTX 1 or KTX 2 file,
+    /// including all mip levels stored in the file.
+    pub fn from_ktx<P>(path: P, settings: &TextureSettings) -> Result<Self, Error>
+        where P: AsRef<Path>
+    {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Texture::from_ktx_bytes(&bytes, settings)
+    }
+
+    /// Loads a compressed texture from KTX 1 or KTX 2 data in memory,
+    /// including all mip levels stored in the data.
+    ///
+    /// Mip chains may stop before 1x1. OpenGL ES 2.0 without `GL_APPLE_texture_max_level`
+    /// can not sample those, so it only samples their base level.
+    pub fn from_ktx_bytes(bytes: &[u8], settings: &TextureSettings) -> Result<Self, Error> {
+        let image = ktx::parse(bytes)?;
+        check_compressed_format(image.internal_format)?;
+
+        let size = [image.width, image.height];
+        let format = TextureFormat::Compressed(image.internal_format);
+        let filters = [settings.get_gl_min_with_mipmaps(samples_mip_levels(image.levels.len(),
+                                                                           size)),
+                       settings.get_gl_mag()];
+        let id = unsafe {
+            upload(&image.levels,
+                   size,
+                   format,
+                   false,
+                   filters,
+                   [Wrap::ClampToEdge; 2],
+                   false)
+        };
+
+        let texture = Texture::with_source(id,
+                                           size,
+                                           format,
+                                           false,
+                                           filters,
+                                           false,
+                                           levels_source(&image.levels));
+        check_gl_error("glCompressedTexImage2D")?;
+        Ok(texture)
     }
 
     /// Creates a texture from image.
-    pub fn from_image(img: &RgbaImage, settings: &TextureSettings) -> Self {
+    pub fn from_image(img: &RgbaImage, settings: &TextureSettings) -> Result<Self, Error> {
         let (width, height) = img.dimensions();
-        CreateTexture::create(&mut (), Format::Rgba8, img, [width, height], settings).unwrap()
+        CreateTexture::create(&mut (), Format::Rgba8, img, [width, height], settings)
+    }
+
+    /// Creates a texture from image, premultiplying the colors with alpha.
+    ///
+    /// Textures stored as sRGB are premultiplied in linear space.
+    /// Data passed to later updates must be premultiplied the same way,
+    /// with `premultiply_alpha_linear` if `is_srgb` returns `true`
+    /// and with `premultiply_alpha` otherwise.
+    pub fn from_image_premultiplied(img: &RgbaImage,
+                                    settings: &TextureSettings)
+                                    -> Result<Self, Error> {
+        let mut img = img.clone();
+        // Rgba8 textures are stored as sRGB whether or not mipmaps are generated.
+        if use_srgb(TextureFormat::Rgba8, false) {
+            premultiply_alpha_linear(&mut img);
+        } else {
+            premultiply_alpha(&mut img);
+        }
+        let mut texture = Texture::from_image(&img, settings)?;
+        texture.set_alpha_mode(AlphaMode::Premultiplied);
+        Ok(texture)
+    }
+
+    /// Reads back the texture contents.
+    ///
+    /// OpenGL ES can not read textures directly,
+    /// so the texture is attached to a temporary framebuffer object.
+    /// Returns `Error::UnsupportedFormat` for formats OpenGL ES can not render to,
+    /// which are the compressed formats, RGB stored as sRGB,
+    /// and the alpha and luminance formats outside of desktop core profiles.
+    ///
+    /// Textures sharing another with `region`, such as glyphs of a `GlyphCache`,
+    /// read back only their region.
+    pub fn to_image(&self) -> Result<RgbaImage, Error> {
+        let format = self.record.format;
+        if !format.is_color_renderable(self.record.srgb) {
+            return Err(Error::UnsupportedFormat(format!("Can not read back a {:?} texture, \
+                                                         which is not color-renderable",
+                                                        format)));
+        }
+        let status;
+        let pixels;
+        unsafe {
+            let mut previous = 0;
+            gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut previous);
+
+            let mut fbo = 0;
+            gl::GenFramebuffers(1, &mut fbo);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, fbo);
+            gl::FramebufferTexture2D(gl::FRAMEBUFFER,
+                                     gl::COLOR_ATTACHMENT0,
+                                     gl::TEXTURE_2D,
+                                     self.get_id(),
+                                     0);
+            status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+            pixels = if status == gl::FRAMEBUFFER_COMPLETE {
+                // Texture rows are stored in upload order, no flipping needed.
+                let rect = self.region.unwrap_or([0, 0, self.record.width, self.record.height]);
+                read_rgba(rect[0] as i32, rect[1] as i32, rect[2], rect[3])
+            } else {
+                Vec::new()
+            };
+
+            gl::BindFramebuffer(gl::FRAMEBUFFER, previous as GLuint);
+            gl::DeleteFramebuffers(1, &fbo);
+        }
+
+        if status != gl::FRAMEBUFFER_COMPLETE {
+            return Err(Error::FramebufferIncomplete(status));
+        }
+        let (width, height) = self.size();
+        Ok(RgbaImage::from_raw(width, height, pixels).unwrap())
+    }
+
+    /// Updates a region of the texture with pixels in the format of the texture.
+    ///
+    /// Affects batched draws of the texture, see `GlGraphics::flush`.
+    pub fn update_region(&mut self,
+                         memory: &[u8],
+                         offset: [u32; 2],
+                         size: [u32; 2])
+                         -> Result<(), Error> {
+        let format = self.record.format;
+        let width = self.record.width;
+        check_memory_size(format, memory, size)?;
+        if offset[0] + size[0] > width || offset[1] + size[1] > self.record.height {
+            return Err(Error::InvalidData(format!("Region at {:?} of size {:?} is outside \
+                                                   of the texture",
+                                                  offset,
+                                                  size)));
+        }
+        let (_, data_format) = format.get_gl_formats(self.record.srgb);
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.get_id());
+            with_unpack_alignment(|| {
+                gl::TexSubImage2D(gl::TEXTURE_2D,
+                                  0,
+                                  offset[0] as i32,
+                                  offset[1] as i32,
+                                  size[0] as i32,
+                                  size[1] as i32,
+                                  data_format,
+                                  gl::UNSIGNED_BYTE,
+                                  memory.as_ptr() as *const _);
+            });
+        }
+        if let Source::Levels(ref mut levels) = *self.record.source.borrow_mut() {
+            let bytes = format.bytes_per_pixel().unwrap_or(0) as usize;
+            let row = size[0] as usize * bytes;
+            for y in 0..size[1] as usize {
+                let dst = ((offset[1] as usize + y) * width as usize + offset[0] as usize) * bytes;
+                levels[0][dst..dst + row].copy_from_slice(&memory[y * row..(y + 1) * row]);
+            }
+        }
+        self.base_level_updated();
+
+        Ok(())
     }
 
     /// Updates image with a new one.
-    pub fn update(&mut self, img: &RgbaImage) {
+    ///
+    /// Affects batched draws of the texture, see `GlGraphics::flush`.
+    pub fn update(&mut self, img: &RgbaImage) -> Result<(), Error> {
         let (width, height) = img.dimensions();
 
-        UpdateTexture::update(self, &mut (), Format::Rgba8, img, [0, 0], [width, height]).unwrap();
+        UpdateTexture::update(self, &mut (), Format::Rgba8, img, [0, 0], [width, height])
     }
 }
 
-impl Drop for Texture {
+impl Drop for TextureRecord {
     fn drop(&mut self) {
-        unsafe {
-            let ids = [self.id];
-            gl::DeleteTextures(1, ids.as_ptr());
-            drop(ids);
+        // Ids of a lost context may have been reused by the current one.
+        if context::is_current(self.generation.get()) {
+            unsafe {
+                gl::DeleteTextures(1, &self.id.get());
+            }
         }
     }
 }
 
 impl ImageSize for Texture {
     fn get_size(&self) -> (u32, u32) {
-        (self.width, self.height)
+        self.size()
     }
 }
 
 impl CreateTexture<()> for Texture {
-    type Error = String;
+    type Error = Error;
 
     fn create<S: Into<[u32; 2]>>(_factory: &mut (),
-                                 _format: Format,
+                                 format: Format,
                                  memory: &[u8],
                                  size: S,
                                  settings: &TextureSettings)
                                  -> Result<Self, Self::Error> {
         let size = size.into();
-        let mut id: GLuint = 0;
-        unsafe {
-            gl::GenTextures(1, &mut id);
-            gl::BindTexture(gl::TEXTURE_2D, id);
-            gl::TexParameteri(gl::TEXTURE_2D,
-                              gl::TEXTURE_MIN_FILTER,
-                              settings.get_gl_min() as i32);
-            gl::TexParameteri(gl::TEXTURE_2D,
-                              gl::TEXTURE_MAG_FILTER,
-                              settings.get_gl_mag() as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
-            if settings.get_generate_mipmap() {
-                gl::GenerateMipmap(gl::TEXTURE_2D);
-            }
-            gl::TexImage2D(gl::TEXTURE_2D,
-                           0,
-                           gl::RGBA as i32,
-                           size[0] as i32,
-                           size[1] as i32,
-                           0,
-                           gl::RGBA,
-                           gl::UNSIGNED_BYTE,
-                           memory.as_ptr() as *const _);
-        }
-
-        Ok(Texture::new(id, size[0], size[1]))
+        Texture::from_memory(memory, size[0], size[1], format.into(), settings)
     }
 }
 
 impl UpdateTexture<()> for Texture {
-    type Error = String;
+    type Error = Error;
 
     fn update<O: Into<[u32; 2]>, S: Into<[u32; 2]>>(&mut self,
                                                     _factory: &mut (),
-                                                    _format: Format,
+                                                    format: Format,
                                                     memory: &[u8],
                                                     offset: O,
                                                     size: S)
                                                     -> Result<(), Self::Error> {
-        let offset = offset.into();
-        let size = size.into();
-        unsafe {
-            gl::BindTexture(gl::TEXTURE_2D, self.id);
-            gl::TexSubImage2D(gl::TEXTURE_2D,
-                              0,
-                              offset[0] as i32,
-                              offset[1] as i32,
-                              size[0] as i32,
-                              size[1] as i32,
-                              gl::RGBA,
-                              gl::UNSIGNED_BYTE,
-                              memory.as_ptr() as *const _);
+        let format = TextureFormat::from(format);
+        if format != self.record.format {
+            return Err(Error::InvalidData(format!("Can not update a {:?} texture with {:?} pixels",
+                                                  self.record.format,
+                                                  format)));
         }
-
-        Ok(())
+        self.update_region(memory, offset.into(), size.into())
     }
 }
 
 impl graphics::ImageSize for Texture {
     fn get_size(&self) -> (u32, u32) {
-        (self.width, self.height)
+        self.size()
+    }
+}
+
+#[test]
+fn test_premultiply_alpha() {
+    let mut pixels = [255, 128, 0, 128, 10, 20, 30, 255, 200, 200, 200, 0];
+    premultiply_alpha(&mut pixels);
+    assert_eq!(pixels, [128, 64, 0, 128, 10, 20, 30, 255, 0, 0, 0, 0]);
+
+    let mut pixels = [255, 128, 0, 128, 10, 20, 30, 255, 200, 200, 200, 0];
+    premultiply_alpha_linear(&mut pixels);
+    assert_eq!(pixels, [188, 93, 0, 128, 10, 20, 30, 255, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_premultiplied_srgb_upload() {
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    let img = RgbaImage::from_raw(1, 1, vec![128, 128, 128, 128]).unwrap();
+    let settings = TextureSettings::new();
+    color_space::set_current(ColorSpace::Linear);
+    let texture = Texture::from_image_premultiplied(&img, &settings).unwrap();
+    color_space::set_current(ColorSpace::Srgb);
+    assert!(texture.is_srgb());
+    let uploads = mock.calls_to("glTexImage2D");
+    assert_eq!(uploads[0].data, Some(vec![93, 93, 93, 128]));
+
+    let texture = Texture::from_image_premultiplied(&img, &settings).unwrap();
+    assert!(!texture.is_srgb());
+    let uploads = mock.calls_to("glTexImage2D");
+    assert_eq!(uploads[1].data, Some(vec![64, 64, 64, 128]));
+}
+
+#[test]
+fn test_core_profile_without_swizzle() {
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    mock.set_string(gl::VERSION, "3.2.0 Mock");
+    mock.set_integer(0x9126, &[1]);
+    assert!(capabilities::current().core_profile);
+    assert!(!capabilities::current().texture_swizzle);
+
+    let settings = TextureSettings::new();
+    let texture = Texture::from_memory_alpha(&[255], 1, 1, &settings).unwrap();
+    assert_eq!(texture.get_format().coverage_channel(), Some(0));
+    assert_eq!(mock.calls_to("glTexImage2D")[0].int(2), gl::R8 as i64);
+    let swizzle = gl::TEXTURE_SWIZZLE_A as i64;
+    assert!(!mock.calls_to("glTexParameteri").iter().any(|c| c.int(1) == swizzle));
+    assert!(Texture::from_memory(&[255; 2], 1, 1, TextureFormat::LuminanceAlpha8, &settings)
+        .is_err());
+}
+
+#[test]
+fn test_keep_pixels() {
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    let settings = TextureSettings::new();
+    let _dropped = Texture::from_memory_alpha(&[1], 1, 1, &settings).unwrap();
+    set_keep_pixels(true);
+    let _kept = Texture::from_memory_alpha(&[2], 1, 1, &settings).unwrap();
+    set_keep_pixels(false);
+
+    mock.clear();
+    context::context_lost();
+    recreate_textures().unwrap();
+    let uploads = mock.calls_to("glTexImage2D");
+    assert_eq!(uploads.len(), 1);
+    assert_eq!(uploads[0].data, Some(vec![2]));
+}
+
+#[test]
+fn test_reload_checks_srgb() {
+    use crate::testing::MockGl;
+
+    let _mock = MockGl::new();
+    let img = RgbaImage::from_raw(1, 1, vec![0; 4]).unwrap();
+    color_space::set_current(ColorSpace::Linear);
+    let mut texture = Texture::from_image(&img, &TextureSettings::new()).unwrap();
+    color_space::set_current(ColorSpace::Srgb);
+    assert!(texture.is_srgb());
+    texture.set_reload(move || Texture::from_image(&img, &TextureSettings::new()));
+
+    context::context_lost();
+    assert!(recreate_textures().is_err());
+}
+
+#[test]
+fn test_to_image_unsupported_format() {
+    use crate::testing::MockGl;
+
+    let mock = MockGl::new();
+    let settings = TextureSettings::new();
+    let texture = Texture::from_memory_alpha(&[255], 1, 1, &settings).unwrap();
+    match texture.to_image() {
+        Err(Error::UnsupportedFormat(_)) => {}
+        _ => panic!("Read back an alpha texture"),
     }
+    assert_eq!(mock.count("glGenFramebuffers"), 0);
+
+    let texture = Texture::from_image(&RgbaImage::new(1, 1), &settings).unwrap();
+    assert!(texture.to_image().is_ok());
+}
+
+#[test]
+fn test_ktx_truncated_mip_chain() {
+    use crate::ktx::{ktx1_header, ETC1_RGB8_OES};
+    use crate::testing::MockGl;
+
+    // Two of the four levels of an 8x8 image.
+    let mut bytes = ktx1_header(2);
+    bytes.extend_from_slice(&[32, 0, 0, 0]);
+    bytes.extend_from_slice(&[1; 32]);
+    bytes.extend_from_slice(&[8, 0, 0, 0]);
+    bytes.extend_from_slice(&[2; 8]);
+    let settings = TextureSettings::new();
+    let parameter = |mock: &MockGl, pname: gl::types::GLenum| {
+        mock.calls_to("glTexParameteri")
+            .iter()
+            .find(|c| c.int(1) == pname as i64)
+            .map(|c| c.int(2))
+    };
+
+    let mock = MockGl::new();
+    mock.set_integer(gl::NUM_COMPRESSED_TEXTURE_FORMATS, &[1]);
+    mock.set_integer(gl::COMPRESSED_TEXTURE_FORMATS, &[ETC1_RGB8_OES as gl::types::GLint]);
+    Texture::from_ktx_bytes(&bytes, &settings).unwrap();
+    assert_eq!(parameter(&mock, gl::TEXTURE_MAX_LEVEL), Some(1));
+    assert_eq!(parameter(&mock, gl::TEXTURE_MIN_FILTER),
+               Some(gl::LINEAR_MIPMAP_LINEAR as i64));
+
+    let mock = MockGl::new();
+    mock.set_string(gl::VERSION, "OpenGL ES 2.0 Mock");
+    mock.set_integer(gl::NUM_COMPRESSED_TEXTURE_FORMATS, &[1]);
+    mock.set_integer(gl::COMPRESSED_TEXTURE_FORMATS, &[ETC1_RGB8_OES as gl::types::GLint]);
+    Texture::from_ktx_bytes(&bytes, &settings).unwrap();
+    assert_eq!(parameter(&mock, gl::TEXTURE_MAX_LEVEL), None);
+    assert_eq!(parameter(&mock, gl::TEXTURE_MIN_FILTER), Some(gl::LINEAR as i64));
+}
+
+#[test]
+fn test_memory_truncated_mip_chain() {
+    use crate::testing::MockGl;
+
+    let settings = TextureSettings::new();
+    let levels: [&[u8]; 2] = [&[0; 16], &[0; 4]];
+    let parameter = |mock: &MockGl, pname: gl::types::GLenum| {
+        mock.calls_to("glTexParameteri")
+            .iter()
+            .find(|c| c.int(1) == pname as i64)
+            .map(|c| c.int(2))
+    };
+
+    let mock = MockGl::new();
+    Texture::from_memory_levels(&levels, 4, 4, TextureFormat::Alpha8, &settings).unwrap();
+    assert_eq!(parameter(&mock, gl::TEXTURE_MAX_LEVEL), Some(1));
+    assert_eq!(parameter(&mock, gl::TEXTURE_MIN_FILTER),
+               Some(gl::LINEAR_MIPMAP_LINEAR as i64));
+
+    // A full chain needs no limit.
+    let mock = MockGl::new();
+    let full: [&[u8]; 3] = [&[0; 16], &[0; 4], &[0; 1]];
+    Texture::from_memory_levels(&full, 4, 4, TextureFormat::Alpha8, &settings).unwrap();
+    assert_eq!(parameter(&mock, gl::TEXTURE_MAX_LEVEL), None);
+
+    let mock = MockGl::new();
+    mock.set_string(gl::VERSION, "OpenGL ES 2.0 Mock");
+    Texture::from_memory_levels(&levels, 4, 4, TextureFormat::Alpha8, &settings).unwrap();
+    assert_eq!(parameter(&mock, gl::TEXTURE_MAX_LEVEL), None);
+    assert_eq!(parameter(&mock, gl::TEXTURE_MIN_FILTER), Some(gl::LINEAR as i64));
+
+    let extra: [&[u8]; 4] = [&[0; 16], &[0; 4], &[0; 1], &[0; 1]];
+    assert!(Texture::from_memory_levels(&extra, 4, 4, TextureFormat::Alpha8, &settings).is_err());
+}
+
+#[test]
+fn test_to_image_stored_format() {
+    use crate::testing::MockGl;
+
+    // Core profiles store alpha textures as red, which can be read back.
+    let mock = MockGl::new();
+    mock.set_string(gl::VERSION, "3.3.0 Mock");
+    mock.set_integer(0x9126, &[1]);
+    let settings = TextureSettings::new();
+    let texture = Texture::from_memory_alpha(&[0; 16], 4, 4, &settings).unwrap();
+    assert!(texture.to_image().is_ok());
+
+    let image = texture.region([1, 2, 3, 1]).to_image().unwrap();
+    assert_eq!(image.dimensions(), (3, 1));
+    let read = mock.calls_to("glReadPixels");
+    let rect: Vec<i64> = (0..4).map(|i| read.last().unwrap().int(i)).collect();
+    assert_eq!(rect, [1, 2, 3, 1]);
 }